@@ -0,0 +1,483 @@
+//! Board wiring and process defaults, read from a JSON file.
+//!
+//! The file is looked up in `NANODRIVER_CONFIG`, then `nanodriver.json` in the working directory.
+//! Without either the built-in defaults describe the single EVAL board the crate was written for.
+//!
+//! ```json
+//! {
+//!     "server": { "bind": "127.0.0.1:8080" },
+//!     "log": { "path": "nanodriver.log" },
+//!     "boards": [
+//!         { "name": "rack1", "serial": "FT5RA1", "vref": 4.0, "span": [-8.0, 8.0] },
+//!         { "name": "rack2", "serial": "FT5RB7", "channel": "B", "chip": "AD5372",
+//!           "backend": "mpsse",
+//!           "pins": { "cs": "AD3", "busy": "AD4", "ldac": "AD5", "reset": "AD6", "clr": "AD7" },
+//!           "clock_hz": 5000000, "spi_mode": 1, "cs_high_ns": 20, "inter_frame_ns": 270,
+//!           "busy_timeout_ms": 10, "hw_reset": true, "verify": true, "startup_code": 32768 },
+//!         { "name": "pi", "spidev": { "spidev": "/dev/spidev0.0", "gpiochip": "/dev/gpiochip0",
+//!           "busy": 22, "ldac": 23, "reset": 24, "clr": 25 }, "busy_timeout_ms": 10 }
+//!     ]
+//! }
+//! ```
+use std::{env, fs, path::Path, time::Duration};
+
+use embedded_hal::spi::{MODE_0, MODE_1, MODE_2, MODE_3};
+use serde::Deserialize;
+
+use crate::{
+    dac::ad537x::{
+        chip::Chip,
+        device::{Backend, DeviceBuilder, FtdiChannel, PinMap, SpidevBus},
+        driver::{DriverConfig, AD5370},
+        reg::ChannelAddress,
+        timing,
+    },
+    error::IError,
+    interface::spi::SpiConfig,
+};
+
+pub const CONFIG_ENV: &str = "NANODRIVER_CONFIG";
+pub const CONFIG_FILE: &str = "nanodriver.json";
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub log: LogConfig,
+    /// The first board is the one behind `GLOBAL_AD5370`.
+    pub boards: Vec<BoardConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            server: ServerConfig::default(),
+            log: LogConfig::default(),
+            boards: vec![BoardConfig::default()],
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Address the HTTP server listens on.
+    pub bind: String,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind: "127.0.0.1:8080".to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// File the FFI layer appends its log lines to, created if missing. Relative paths are
+    /// taken from the working directory.
+    pub path: String,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            path: "nanodriver.log".to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BoardConfig {
+    /// Name in the board registry.
+    pub name: String,
+    /// Serial number of the FT4232H, without the channel letter. Mutually exclusive with `index`.
+    pub serial: Option<String>,
+    /// Index in the D2XX device list, used when no serial is given. Defaults to 0.
+    pub index: Option<i32>,
+    /// Channel of the board given by `serial`, "A" when omitted. An index already selects one.
+    pub channel: Option<FtdiChannel>,
+    /// "hal", or "mpsse" to batch bus operations and read BUSY. Defaults to "mpsse" when
+    /// `verify` is set and to "hal" otherwise.
+    pub backend: Option<Backend>,
+    /// Linux SPI controller used instead of an FTDI device.
+    pub spidev: Option<SpidevBus>,
+    pub pins: PinMap,
+    /// Part name, e.g. "AD5370".
+    pub chip: String,
+    pub vref: f64,
+    /// SCLK frequency.
+    pub clock_hz: u32,
+    /// SPI mode, 1 or 2 for the AD537x on spidev, 1 on the FTDI backends.
+    pub spi_mode: u8,
+    /// Minimum CS high time between frames.
+    pub cs_high_ns: u64,
+    /// Minimum gap between the request and data frames of a readback.
+    pub inter_frame_ns: u64,
+    /// Read and write timeout of the USB transfers.
+    pub usb_timeout_ms: u64,
+    /// BUSY wait limit, `null` when BUSY can not be read back. Needs the "mpsse" backend.
+    pub busy_timeout_ms: Option<u64>,
+    /// Whether RESET is wired to the adapter.
+    pub hw_reset: bool,
+    /// Read back every register write and repeat it on a mismatch.
+    pub verify: bool,
+    /// Repeats of a write that reads back wrong before it fails.
+    pub verify_retries: u8,
+    /// Output span `[min, max]` in volts, set through the offset DACs at startup.
+    pub span: Option<(f64, f64)>,
+    /// Input code written to every channel at startup.
+    pub startup_code: Option<u16>,
+}
+
+impl Default for BoardConfig {
+    /// The EVAL board on the first FTDI device.
+    fn default() -> Self {
+        Self {
+            name: "default".to_string(),
+            serial: None,
+            index: None,
+            channel: None,
+            backend: None,
+            spidev: None,
+            pins: PinMap::default(),
+            chip: Chip::AD5370.name.to_string(),
+            vref: 4.0,
+            clock_hz: 10_000_000,
+            spi_mode: 1,
+            cs_high_ns: 20,
+            inter_frame_ns: 270,
+            usb_timeout_ms: 1000,
+            // BUSY is wired to AD4 but the hal backend can not sample it.
+            busy_timeout_ms: None,
+            // RESET is left to the button on the EVAL board, please keep LK3 connected.
+            hw_reset: false,
+            verify: false,
+            verify_retries: 2,
+            span: None,
+            startup_code: None,
+        }
+    }
+}
+
+impl BoardConfig {
+    fn errors(&self, errors: &mut Vec<String>) {
+        let mut err = |msg: String| errors.push(format!("board \"{}\": {}", self.name, msg));
+        if self.name.is_empty() || self.name.contains(':') {
+            err("name must be non-empty and can not contain ':'".to_string());
+        }
+        if self.serial.is_some() && self.index.is_some() {
+            err("give either serial or index, not both".to_string());
+        }
+        if self.spidev.is_some() && (self.serial.is_some() || self.index.is_some()) {
+            err("give either spidev or an FTDI serial or index".to_string());
+        }
+        match self.channel {
+            Some(channel) if self.serial.is_none() => err(format!(
+                "channel {:?} needs a serial, an index or spidev already selects the port",
+                channel
+            )),
+            Some(channel @ FtdiChannel::C) | Some(channel @ FtdiChannel::D) => {
+                err(format!("channel {:?} has no MPSSE engine", channel))
+            }
+            _ => {}
+        }
+        if let Err(e) = self.pins.check() {
+            err(format!("pins: {}", e));
+        }
+        let chip = Chip::by_name(&self.chip);
+        if chip.is_none() {
+            err(format!("unknown chip {:?}", self.chip));
+        }
+        if !(self.vref > 0.0 && self.vref <= 5.0) {
+            err(format!("vref must be in (0, 5] V, got {}", self.vref));
+        }
+        match self.spi_config() {
+            Some(spi) => {
+                if let Err(e) = timing::AD537X.check(&spi) {
+                    err(format!("spi: {}", e));
+                } else if let (None, Err(e)) = (&self.spidev, spi.check_ftdi_mode()) {
+                    err(format!("spi: {}", e));
+                }
+            }
+            None => err(format!("spi_mode must be 0 to 3, got {}", self.spi_mode)),
+        }
+        let backend = self
+            .backend
+            .unwrap_or_else(|| Backend::for_verify(self.verify));
+        if self.busy_timeout_ms.is_some() && backend == Backend::Hal && self.spidev.is_none() {
+            err("busy_timeout_ms needs the \"mpsse\" backend or spidev to read BUSY".to_string());
+        }
+        if self.usb_timeout_ms == 0 {
+            err("usb_timeout_ms must be positive".to_string());
+        }
+        if let Some((min, max)) = self.span {
+            let width = 4.0 * self.vref;
+            let step = width / 16384.0;
+            if ((max - min) - width).abs() > step {
+                err(format!(
+                    "span [{}, {}] must be 4 * vref = {} V wide",
+                    min, max, width
+                ));
+            } else if min > 0.0 || -min > width - step {
+                // The offset DAC tops out one step short of the full width, see `set_output_span`.
+                err(format!(
+                    "span [{}, {}] must contain 0 V and start above -{} V",
+                    min, max, width
+                ));
+            }
+        }
+        if let (Some(code), Some(chip)) = (self.startup_code, chip) {
+            if code > chip.max_code() {
+                err(format!(
+                    "startup_code 0x{:X} exceeds the {}-bit range of the {}",
+                    code, chip.bits, chip.name
+                ));
+            }
+        }
+    }
+
+    fn spi_config(&self) -> Option<SpiConfig> {
+        let mode = match self.spi_mode {
+            0 => MODE_0,
+            1 => MODE_1,
+            2 => MODE_2,
+            3 => MODE_3,
+            _ => return None,
+        };
+        Some(SpiConfig {
+            mode,
+            clock_hz: self.clock_hz,
+            cs_high: Duration::from_nanos(self.cs_high_ns),
+            inter_frame: Duration::from_nanos(self.inter_frame_ns),
+        })
+    }
+
+    pub fn builder(&self) -> DeviceBuilder {
+        let builder = match (&self.spidev, &self.serial) {
+            (Some(bus), _) => DeviceBuilder::spidev(bus.clone()),
+            (None, Some(serial)) => match self.channel {
+                Some(channel) => DeviceBuilder::serial(serial).channel(channel),
+                None => DeviceBuilder::serial(serial),
+            },
+            (None, None) => DeviceBuilder::index(self.index.unwrap_or(0)),
+        };
+        let builder = match self.backend {
+            Some(backend) => builder.backend(backend),
+            None => builder,
+        };
+        builder
+            .pins(self.pins)
+            .chip(Chip::by_name(&self.chip).unwrap_or_default())
+            .vref(self.vref)
+            .spi(self.spi_config().unwrap_or_default())
+            .usb_timeout(Duration::from_millis(self.usb_timeout_ms))
+            .config(DriverConfig {
+                busy_timeout: self.busy_timeout_ms.map(Duration::from_millis),
+                hw_reset: self.hw_reset,
+                verify: self.verify,
+                verify_retries: self.verify_retries,
+            })
+    }
+
+    /// Applies the startup span and output code to an opened board.
+    pub fn apply(&self, dev: &mut AD5370) -> Result<(), IError> {
+        if let Some((min, max)) = self.span {
+            dev.set_output_span(min, max)?;
+        }
+        if let Some(code) = self.startup_code {
+            dev.set_code(code, ChannelAddress::AllCh)?;
+            dev.pulse_ldac()?;
+        }
+        Ok(())
+    }
+
+    /// Opens the board and applies the startup settings.
+    pub fn open(&self) -> Result<AD5370<'static>, IError> {
+        let mut dev = self.builder().build()?;
+        self.apply(&mut dev)?;
+        Ok(dev)
+    }
+}
+
+impl Config {
+    /// Parses and validates a JSON config.
+    pub fn from_json(text: &str) -> Result<Self, IError> {
+        let cfg: Config = serde_json::from_str(text).map_err(|e| IError::Config {
+            msg: format!("line {} column {}: {}", e.line(), e.column(), e),
+        })?;
+        cfg.validate()?;
+        Ok(cfg)
+    }
+
+    /// Reads the file at `path`.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, IError> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path).map_err(|e| IError::Config {
+            msg: format!("{}: {}", path.display(), e),
+        })?;
+        Self::from_json(&text).map_err(|e| match e {
+            IError::Config { msg } => IError::Config {
+                msg: format!("{}: {}", path.display(), msg),
+            },
+            e => e,
+        })
+    }
+
+    /// Reads the file named by `NANODRIVER_CONFIG`, or `nanodriver.json` if it exists, or falls
+    /// back to the defaults.
+    pub fn load() -> Result<Self, IError> {
+        if let Ok(path) = env::var(CONFIG_ENV) {
+            return Self::from_file(path);
+        }
+        if Path::new(CONFIG_FILE).exists() {
+            return Self::from_file(CONFIG_FILE);
+        }
+        Ok(Self::default())
+    }
+
+    /// Checks every board and reports all problems at once.
+    pub fn validate(&self) -> Result<(), IError> {
+        let mut errors = Vec::new();
+        if self.boards.is_empty() {
+            errors.push("at least one board is required".to_string());
+        }
+        for (i, board) in self.boards.iter().enumerate() {
+            if self.boards[..i].iter().any(|b| b.name == board.name) {
+                errors.push(format!("board \"{}\": name used twice", board.name));
+            }
+            board.errors(&mut errors);
+        }
+        if self.server.bind.is_empty() {
+            errors.push("server.bind is empty".to_string());
+        }
+        if errors.is_empty() {
+            return Ok(());
+        }
+        Err(IError::Config {
+            msg: errors.join("; "),
+        })
+    }
+
+    pub fn board(&self, name: &str) -> Option<&BoardConfig> {
+        self.boards.iter().find(|b| b.name == name)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{dac::ad537x::sim::Simulator, interface::gpio::Pin};
+
+    #[test]
+    fn test_parse() {
+        let cfg = Config::from_json(
+            r#"{
+                "server": { "bind": "0.0.0.0:9000" },
+                "boards": [
+                    { "name": "rack1", "serial": "FT5RA1", "span": [-8.0, 8.0] },
+                    { "name": "rack2", "index": 2, "chip": "ad5372", "vref": 2.5,
+                      "pins": { "cs": "AD4", "busy": "AD3" }, "backend": "mpsse",
+                      "busy_timeout_ms": 10, "verify": true },
+                    { "name": "pi", "busy_timeout_ms": 10,
+                      "spidev": { "spidev": "/dev/spidev0.0", "gpiochip": "/dev/gpiochip0",
+                                  "busy": 22, "ldac": 23, "reset": 24, "clr": 25 } }
+                ]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(cfg.server.bind, "0.0.0.0:9000");
+        assert_eq!(cfg.log.path, "nanodriver.log");
+        assert_eq!(cfg.boards[0].span, Some((-8.0, 8.0)));
+        assert_eq!(cfg.boards[0].clock_hz, 10_000_000);
+        let rack2 = cfg.board("rack2").unwrap();
+        assert_eq!(rack2.pins.cs, Pin::AD4);
+        assert_eq!(rack2.pins.ldac, Pin::AD5);
+        assert_eq!(rack2.backend, Some(Backend::Mpsse));
+        assert!(rack2.verify && !cfg.boards[0].verify);
+        let pi = cfg.board("pi").unwrap().spidev.as_ref().unwrap();
+        assert_eq!((pi.spidev.as_str(), pi.busy), ("/dev/spidev0.0", 22));
+        assert_eq!(Chip::by_name(&rack2.chip), Some(Chip::AD5372));
+
+        assert_eq!(Config::from_json("{}").unwrap(), Config::default());
+    }
+
+    #[test]
+    fn test_validation_errors() {
+        let msg = |text: &str| match Config::from_json(text) {
+            Err(IError::Config { msg }) => msg,
+            res => panic!("expected a config error, got {:?}", res),
+        };
+        let m = msg(
+            r#"{ "boards": [ { "name": "a", "vref": 7.0, "chip": "AD5399" },
+                                     { "name": "a", "serial": "X", "index": 1 } ] }"#,
+        );
+        assert!(m.contains("vref must be in (0, 5] V, got 7"), "{}", m);
+        assert!(m.contains("unknown chip \"AD5399\""), "{}", m);
+        assert!(m.contains("name used twice"), "{}", m);
+        assert!(m.contains("either serial or index"), "{}", m);
+
+        let m = msg(
+            r#"{ "boards": [ { "span": [-5.0, 5.0], "startup_code": 65535,
+                                       "chip": "AD5373", "pins": { "cs": "AD0" } } ] }"#,
+        );
+        assert!(m.contains("16 V wide"), "{}", m);
+        assert!(m.contains("14-bit range"), "{}", m);
+        assert!(m.contains("pins: AD0 to AD2"), "{}", m);
+
+        let m = msg(r#"{ "boards": [ { "span": [-16.0, 0.0] } ] }"#);
+        assert!(
+            m.contains("must contain 0 V and start above -16 V"),
+            "{}",
+            m
+        );
+
+        let m = msg(r#"{ "boards": [ { "clock_hz": 25000000, "spi_mode": 0 } ] }"#);
+        assert!(m.contains("spi: spi mode"), "{}", m);
+        let m = msg(r#"{ "boards": [ { "spi_mode": 2 } ] }"#);
+        assert!(
+            m.contains("spi: the ftdi backends only implement spi mode 1"),
+            "{}",
+            m
+        );
+        let m = msg(r#"{ "boards": [ { "clock_hz": 25000000 } ] }"#);
+        assert!(m.contains("spi: spi clock out of range: 25000000"), "{}", m);
+
+        let m = msg(r#"{ "boards": [ { "busy_timeout_ms": 10 } ] }"#);
+        assert!(
+            m.contains("busy_timeout_ms needs the \"mpsse\" backend"),
+            "{}",
+            m
+        );
+
+        let m = msg(
+            r#"{ "boards": [ { "index": 1, "spidev": { "spidev": "/dev/spidev0.0",
+                 "gpiochip": "/dev/gpiochip0", "busy": 1, "ldac": 2, "reset": 3, "clr": 4 } } ] }"#,
+        );
+        assert!(m.contains("either spidev or an FTDI"), "{}", m);
+
+        let m = msg(r#"{ "boards": [ { "index": 1, "channel": "B" } ] }"#);
+        assert!(m.contains("channel B needs a serial"), "{}", m);
+
+        let m = msg(r#"{ "boards": [ { "vreff": 4.0 } ] }"#);
+        assert!(m.starts_with("line 1 column"), "{}", m);
+        assert!(m.contains("vreff"), "{}", m);
+    }
+
+    #[test]
+    fn test_apply() {
+        let board = BoardConfig {
+            span: Some((-8.0, 8.0)),
+            startup_code: Some(0x8000),
+            ..BoardConfig::default()
+        };
+        let sim = Simulator::new();
+        let mut dev = sim.driver(board.vref);
+        board.apply(&mut dev).unwrap();
+        assert_eq!(sim.registers().ofs0, 0x2000);
+        assert_eq!(sim.dac_codes()[39], 0x8000);
+    }
+}
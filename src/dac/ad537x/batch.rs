@@ -0,0 +1,123 @@
+//! Synchronous multi-channel updates.
+//!
+//! LDAC is held high while the input registers are streamed, then pulsed once so that every
+//! channel written in the batch changes its output at the same instant. The frames are queued
+//! and handed to the bus in one `spi_write_frames` call, so backends that batch bus operations
+//! send the whole update in a single transfer. With verification on, the input registers are
+//! read back before the pulse, so a batch that fails the check leaves the outputs untouched.
+use std::time::{Duration, Instant};
+
+use super::{
+    driver::AD5370,
+    reg::{ChannelAddress, WriteMode},
+};
+use crate::error::IError;
+
+/// Summary of a committed batch.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BatchReport {
+    /// Number of frames written to the input registers.
+    pub frames: usize,
+    /// Time spent inside SPI writes, excluding the LDAC pulse.
+    pub spi_time: Duration,
+}
+
+pub struct Batch<'d, 'a> {
+    dev: &'d mut AD5370<'a>,
+    frames: Vec<[u8; 3]>,
+    writes: Vec<(ChannelAddress, u16)>,
+}
+
+impl<'d, 'a> Batch<'d, 'a> {
+    /// Queues a data write, checked right away.
+    pub fn set_code(&mut self, code: u16, target: ChannelAddress) -> Result<(), IError> {
+        self.frames
+            .push(self.dev.frame(WriteMode::Data, target, code)?);
+        self.writes.push((target, code));
+        Ok(())
+    }
+
+    pub fn set_voltage(&mut self, vol: f64, target: ChannelAddress) -> Result<(), IError> {
+        let code = self.dev.voltage_code(vol, target)?;
+        self.set_code(code, target)
+    }
+}
+
+impl<'a> AD5370<'a> {
+    /// Runs `f` with LDAC held high, then streams the queued writes and pulses LDAC.
+    ///
+    /// If `f` fails nothing is written and the outputs are left untouched.
+    pub fn batch<F>(&mut self, f: F) -> Result<BatchReport, IError>
+    where
+        F: FnOnce(&mut Batch<'_, 'a>) -> Result<(), IError>,
+    {
+        self._ldac.set()?;
+        let mut batch = Batch {
+            dev: self,
+            frames: Vec::new(),
+            writes: Vec::new(),
+        };
+        f(&mut batch)?;
+        let Batch { frames, writes, .. } = batch;
+
+        let start = Instant::now();
+        self.write_frames(&frames)?;
+        let report = BatchReport {
+            frames: frames.len(),
+            spi_time: start.elapsed(),
+        };
+        let writes: Vec<_> = writes
+            .into_iter()
+            .map(|(target, code)| (WriteMode::Data, target, code))
+            .collect();
+        for (mode, target, code) in writes.iter() {
+            self.reg.apply_write(&self.chip, *mode, *target, *code);
+        }
+        self.verify_write(&frames, &writes)?;
+        self.pulse_ldac()?;
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::sim::Simulator;
+    use super::*;
+
+    #[test]
+    fn test_outputs_change_together() {
+        let sim = Simulator::new();
+        let mut dev = sim.driver(4.0);
+        dev.set_gain(0xFFFF, ChannelAddress::AllCh).unwrap();
+        dev.set_offset(0x8000, ChannelAddress::AllCh).unwrap();
+        dev.pulse_ldac().unwrap();
+        let before = sim.dac_codes();
+
+        let report = dev
+            .batch(|b| {
+                for idx in 0..40_u16 {
+                    let target = ChannelAddress::SingleCh {
+                        ch: (idx % 8) as u8,
+                        group: (idx / 8) as u8,
+                    };
+                    b.set_code(0x1000 + idx, target)?;
+                    assert_eq!(sim.dac_codes(), before);
+                }
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(report.frames, 40);
+        for idx in 0..40 {
+            assert_eq!(sim.dac_codes()[idx], 0x1000 + idx as u16);
+        }
+
+        let frames = sim.frames();
+        let res = dev.batch(|b| {
+            b.set_code(0x2000, ChannelAddress::AllCh)?;
+            b.set_voltage(100.0, ChannelAddress::AllCh)
+        });
+        assert!(res.is_err());
+        assert_eq!(sim.frames(), frames);
+        assert_eq!(sim.dac_codes()[0], 0x1000);
+    }
+}
@@ -0,0 +1,97 @@
+//! Descriptors of the AD537x family members.
+//!
+//! All parts share the serial interface, register map and channel addressing. They differ in the
+//! number of groups of eight channels, the DAC resolution and the number of offset DACs. The
+//! 14-bit parts take their X, M and C codes left-justified in the 16-bit data field.
+use super::reg::OffsetDac;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Chip {
+    pub name: &'static str,
+    /// Number of groups of eight channels.
+    pub groups: u8,
+    /// Resolution of the DACs and of the X, M and C registers.
+    pub bits: u8,
+    /// Number of 14-bit offset DACs. OFS0 always drives group 0.
+    pub ofs_count: u8,
+}
+
+impl Chip {
+    pub const AD5370: Chip = Chip {
+        name: "AD5370",
+        groups: 5,
+        bits: 16,
+        ofs_count: 2,
+    };
+    pub const AD5371: Chip = Chip {
+        name: "AD5371",
+        groups: 5,
+        bits: 14,
+        ofs_count: 3,
+    };
+    pub const AD5372: Chip = Chip {
+        name: "AD5372",
+        groups: 4,
+        bits: 16,
+        ofs_count: 2,
+    };
+    pub const AD5373: Chip = Chip {
+        name: "AD5373",
+        groups: 4,
+        bits: 14,
+        ofs_count: 2,
+    };
+
+    pub const ALL: [Chip; 4] = [Chip::AD5370, Chip::AD5371, Chip::AD5372, Chip::AD5373];
+
+    /// Looks a part up by name, e.g. "AD5372".
+    pub fn by_name(name: &str) -> Option<Chip> {
+        Chip::ALL
+            .iter()
+            .find(|c| c.name.eq_ignore_ascii_case(name))
+            .copied()
+    }
+
+    pub fn channels(&self) -> usize {
+        self.groups as usize * 8
+    }
+
+    /// Largest X, M or C code.
+    pub fn max_code(&self) -> u16 {
+        (((1_u32) << self.bits) - 1) as u16
+    }
+
+    /// Offset DAC setting the span of `group`.
+    pub fn ofs_of(&self, group: u8) -> OffsetDac {
+        match (self.ofs_count, group) {
+            (_, 0) => OffsetDac::OFS0,
+            (3, 1) | (3, 2) => OffsetDac::OFS1,
+            (3, _) => OffsetDac::OFS2,
+            _ => OffsetDac::OFS1,
+        }
+    }
+
+    /// Offset DACs present on the part.
+    pub fn offset_dacs(&self) -> &'static [OffsetDac] {
+        match self.ofs_count {
+            3 => &[OffsetDac::OFS0, OffsetDac::OFS1, OffsetDac::OFS2],
+            _ => &[OffsetDac::OFS0, OffsetDac::OFS1],
+        }
+    }
+
+    /// Places an X, M or C code into the data field of a frame.
+    pub fn code_to_field(&self, code: u16) -> u16 {
+        code << (16 - self.bits)
+    }
+
+    /// Extracts an X, M or C code from the data field of a frame.
+    pub fn field_to_code(&self, field: u16) -> u16 {
+        field >> (16 - self.bits)
+    }
+}
+
+impl Default for Chip {
+    fn default() -> Self {
+        Chip::AD5370
+    }
+}
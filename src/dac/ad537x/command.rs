@@ -0,0 +1,192 @@
+//! Typed view of the 24-bit frames, the reverse of `builder`.
+//!
+//! `Command::decode` parses a frame as the chip would, `Command::encode` produces the frame
+//! `MainBuilder` would have built for it. Data fields are kept raw, so X, M and C codes of the
+//! 14-bit parts stay left-justified.
+use std::{convert::TryFrom, fmt::Display};
+
+use super::{
+    builder::{Builder, MainBuilder},
+    reg::{ChannelAddress, ReadBackAddr, SpecialFunctionAddress, WriteMode},
+};
+use crate::error::IError;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Command {
+    /// Write to the X, M or C registers of the channels in `target`.
+    Write {
+        mode: WriteMode,
+        target: ChannelAddress,
+        data: u16,
+    },
+    /// Special function other than a readback request.
+    Function {
+        addr: SpecialFunctionAddress,
+        data: u16,
+    },
+    /// Requests `addr` to be shifted out on SDO during the next frame.
+    ReadBack(ReadBackAddr),
+}
+
+impl Command {
+    pub fn decode(frame: [u8; 3]) -> Result<Self, IError> {
+        let addr = frame[0] & 0x3F;
+        let data = u16::from_be_bytes([frame[1], frame[2]]);
+        if frame[0] >> 6 == 0 {
+            return match SpecialFunctionAddress::try_from(addr)? {
+                SpecialFunctionAddress::ReadBack => {
+                    Ok(Command::ReadBack(ReadBackAddr::try_from(data)?))
+                }
+                addr => Ok(Command::Function { addr, data }),
+            };
+        }
+        Ok(Command::Write {
+            mode: WriteMode::try_from(frame[0] >> 6)?,
+            target: ChannelAddress::try_from(addr)?,
+            data,
+        })
+    }
+
+    /// Whether the frame changes a register, i.e. is neither a readback request nor a NOP.
+    pub fn is_write(self) -> bool {
+        match self {
+            Command::Write { .. } => true,
+            Command::Function { addr, .. } => addr != SpecialFunctionAddress::Nop,
+            Command::ReadBack(_) => false,
+        }
+    }
+
+    pub fn encode(self) -> [u8; 3] {
+        match self {
+            Command::Write { mode, target, data } => MainBuilder::default()
+                .write(mode)
+                .address(target)
+                .data(data)
+                .build(),
+            Command::Function { addr, data } => MainBuilder::default()
+                .funtion()
+                .address(addr)
+                .data(data)
+                .build(),
+            Command::ReadBack(addr) => MainBuilder::default().read(addr).build(),
+        }
+    }
+}
+
+impl TryFrom<[u8; 3]> for Command {
+    type Error = IError;
+    fn try_from(frame: [u8; 3]) -> Result<Self, Self::Error> {
+        Command::decode(frame)
+    }
+}
+
+impl Display for Command {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Command::Write { mode, target, data } => {
+                write!(f, "{:?} {:?} <- 0x{:04X}", mode, target, data)
+            }
+            Command::Function {
+                addr: SpecialFunctionAddress::Nop,
+                ..
+            } => f.write_str("NOP"),
+            Command::Function { addr, data } => write!(f, "{:?} <- 0x{:04X}", addr, data),
+            Command::ReadBack(addr) => write!(f, "ReadBack {:?}", addr),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Data fields exercising every bit, the edges and a spread of values in between.
+    fn data_samples() -> impl Iterator<Item = u16> {
+        (0..=0xFFFF_u32)
+            .step_by(0x0F0F)
+            .map(|d| d as u16)
+            .chain([0x0001, 0x8000, 0xFFFF].iter().copied())
+    }
+
+    #[test]
+    fn test_roundtrip_builder() {
+        let mut count = 0;
+        for addr in 0..=0x3F_u8 {
+            let target = match ChannelAddress::try_from(addr) {
+                Ok(t) => t,
+                Err(_) => continue,
+            };
+            for mode in [WriteMode::Data, WriteMode::Offset, WriteMode::Gain].iter() {
+                for data in data_samples() {
+                    let frame = MainBuilder::default()
+                        .write(*mode)
+                        .address(target)
+                        .data(data)
+                        .build();
+                    let cmd = Command::Write {
+                        mode: *mode,
+                        target,
+                        data,
+                    };
+                    assert_eq!(Command::decode(frame).unwrap(), cmd);
+                    assert_eq!(cmd.encode(), frame);
+                    count += 1;
+                }
+            }
+        }
+        // 1 + 5 groups + 5 * 8 channels + 2 * 8 channel-x addresses.
+        assert_eq!(count, 62 * 3 * data_samples().count());
+
+        for addr in 0..=0x3F_u8 {
+            let addr = match SpecialFunctionAddress::try_from(addr) {
+                Ok(SpecialFunctionAddress::ReadBack) | Err(_) => continue,
+                Ok(a) => a,
+            };
+            for data in data_samples() {
+                let cmd = Command::Function { addr, data };
+                assert_eq!(Command::decode(cmd.encode()).unwrap(), cmd);
+            }
+        }
+
+        let mut count = 0;
+        for data in 0..=0xFFFF_u16 {
+            if let Ok(addr) = ReadBackAddr::try_from(data) {
+                let frame = MainBuilder::default().read(addr).build();
+                assert_eq!(Command::decode(frame).unwrap(), Command::ReadBack(addr));
+                count += 1;
+            }
+        }
+        // 4 kinds * 40 channels + control, 3 offset DACs and 5 select registers, each with
+        // 128 don't care patterns in bits 6:0.
+        assert_eq!(count, (4 * 40 + 9) * 128);
+    }
+
+    #[test]
+    fn test_decode_any_frame() {
+        // Whatever decodes must re-encode to a frame that decodes to the same command.
+        for hi in 0..=0xFF_u8 {
+            for data in data_samples().chain((0..=0xFFFF_u32).step_by(0x7F).map(|d| d as u16)) {
+                let [mid, lo] = data.to_be_bytes();
+                if let Ok(cmd) = Command::decode([hi, mid, lo]) {
+                    assert_eq!(Command::decode(cmd.encode()).unwrap(), cmd);
+                }
+            }
+        }
+        assert!(Command::decode([0x0C, 0, 0]).is_err());
+        assert!(Command::decode([0x05, 0x00, 0x00]).is_err());
+        // Channel addresses 6 and 7 are reserved.
+        assert!(Command::decode([0xC6, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn test_display() {
+        let cmd = Command::decode([0xC0 | (3 << 3) | 2, 0x12, 0x34]).unwrap();
+        assert_eq!(
+            cmd.to_string(),
+            "Data SingleCh { ch: 2, group: 2 } <- 0x1234"
+        );
+        let cmd = Command::decode(MainBuilder::default().read(ReadBackAddr::OFS1).build());
+        assert_eq!(cmd.unwrap().to_string(), "ReadBack OFS1");
+        assert_eq!(Command::decode([0, 0, 0]).unwrap().to_string(), "NOP");
+    }
+}
@@ -0,0 +1,625 @@
+//! Opening an AD537x behind an FT4232H.
+//!
+//! `DeviceBuilder` picks the FTDI device and channel, maps the control pins and configures the
+//! MPSSE engine through the selected `Backend`, then hands back an initialized `AD5370`. Boards
+//! on a Linux SPI controller are opened through a `SpidevBus` instead. Nothing here panics when
+//! the device is missing, so several boards can be opened from one process and a failed open is
+//! just an error.
+use std::{convert::TryFrom, time::Duration};
+
+use ftdi_embedded_hal::{self as hal, FtHal, Initialized, OutputPin as FtOutPin};
+use libftd2xx::{DeviceInfo, DeviceType, Ft4232h, Ftdi, FtdiMpsse, MpsseSettings};
+use serde::Deserialize;
+
+use super::{
+    chip::Chip,
+    driver::{DriverConfig, AD5370},
+    timing,
+};
+use crate::{
+    error::IError,
+    interface::{
+        gpio::{FtdiGPIOController, IOController, Pin},
+        mpsse::{MpsseBus, MpsseGPIOController, MpsseSPIController},
+        spi::{FtdiSPIController, SpiConfig, Transactional},
+    },
+};
+
+/// Channel of an FT4232H. Only A and B have an MPSSE engine.
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize)]
+pub enum FtdiChannel {
+    A,
+    B,
+    C,
+    D,
+}
+
+impl FtdiChannel {
+    fn suffix(self) -> char {
+        match self {
+            FtdiChannel::A => 'A',
+            FtdiChannel::B => 'B',
+            FtdiChannel::C => 'C',
+            FtdiChannel::D => 'D',
+        }
+    }
+
+    fn from_suffix(c: char) -> Option<Self> {
+        match c {
+            'A' => Some(FtdiChannel::A),
+            'B' => Some(FtdiChannel::B),
+            'C' => Some(FtdiChannel::C),
+            'D' => Some(FtdiChannel::D),
+            _ => None,
+        }
+    }
+}
+
+/// One FT4232H found on the USB bus.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FtdiBoard {
+    /// Serial number without the channel letter, as taken by `DeviceBuilder::serial`.
+    pub serial: String,
+    /// Description of the first listed channel, e.g. "Quad RS232-HS A".
+    pub description: String,
+    /// Channels currently opened, by this or another process.
+    pub open: Vec<FtdiChannel>,
+}
+
+/// Lists the attached FT4232H adapters, one entry per board rather than per channel.
+pub fn list_boards() -> Result<Vec<FtdiBoard>, IError> {
+    let devices = libftd2xx::list_devices().map_err(|_| IError::Bus { source: "d2xx" })?;
+    Ok(group_boards(&devices))
+}
+
+fn group_boards(devices: &[DeviceInfo]) -> Vec<FtdiBoard> {
+    let mut boards: Vec<FtdiBoard> = Vec::new();
+    for dev in devices {
+        if dev.device_type != DeviceType::FT4232H {
+            continue;
+        }
+        let mut serial = dev.serial_number.clone();
+        let channel = serial.pop().and_then(FtdiChannel::from_suffix);
+        let idx = match boards.iter().position(|b| b.serial == serial) {
+            Some(idx) => idx,
+            None => {
+                boards.push(FtdiBoard {
+                    serial,
+                    description: dev.description.clone(),
+                    open: Vec::new(),
+                });
+                boards.len() - 1
+            }
+        };
+        if let (true, Some(channel)) = (dev.port_open, channel) {
+            boards[idx].open.push(channel);
+        }
+    }
+    boards
+}
+
+/// How the FTDI device is looked up.
+#[derive(Clone, Debug, PartialEq)]
+pub enum FtdiSelector {
+    /// Serial number of the board, without the channel letter the D2XX driver appends.
+    Serial(String),
+    /// Index in the D2XX device list. Every FT4232H channel is listed on its own, so the index
+    /// already selects the channel.
+    Index(i32),
+}
+
+/// How bus operations reach the FT4232H.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// `ftdi-embedded-hal`, one USB transfer per SPI write or pin change. The control pins are
+    /// outputs only, so BUSY can not be polled.
+    #[default]
+    Hal,
+    /// Raw MPSSE commands queued by `MpsseBus`. Streams of frames go out in one transfer and
+    /// BUSY can be polled.
+    Mpsse,
+}
+
+impl Backend {
+    /// Backend used when none is given: mpsse for verified writes, since only it reads back in
+    /// a single transfer, see `DriverConfig::verify`.
+    pub fn for_verify(verify: bool) -> Self {
+        if verify {
+            Backend::Mpsse
+        } else {
+            Backend::default()
+        }
+    }
+}
+
+/// spidev node and gpiochip lines of a board wired to a Linux SPI controller, which drives CS.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SpidevBus {
+    /// e.g. "/dev/spidev0.0".
+    pub spidev: String,
+    /// e.g. "/dev/gpiochip0".
+    pub gpiochip: String,
+    /// Line offsets on `gpiochip`.
+    pub busy: u32,
+    pub ldac: u32,
+    pub reset: u32,
+    pub clr: u32,
+}
+
+/// GPIOs of the MPSSE port wired to the chip. AD0 to AD2 carry SCK, MOSI and MISO.
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PinMap {
+    pub cs: Pin,
+    pub busy: Pin,
+    pub ldac: Pin,
+    pub reset: Pin,
+    pub clr: Pin,
+}
+
+impl Default for PinMap {
+    /// Wiring of the EVAL-AD5370 adapter.
+    fn default() -> Self {
+        Self {
+            cs: Pin::AD3,
+            busy: Pin::AD4,
+            ldac: Pin::AD5,
+            reset: Pin::AD6,
+            clr: Pin::AD7,
+        }
+    }
+}
+
+impl PinMap {
+    pub(crate) fn check(&self) -> Result<(), IError> {
+        let pins = [self.cs, self.busy, self.ldac, self.reset, self.clr];
+        for (i, pin) in pins.iter().enumerate() {
+            if matches!(pin, Pin::AD0 | Pin::AD1 | Pin::AD2) {
+                return Err(IError::General {
+                    msg: "AD0 to AD2 are used by the SPI bus",
+                });
+            }
+            if pins[..i].contains(pin) {
+                return Err(IError::General {
+                    msg: "pin mapped twice",
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+fn ft_pin(ftdi: &'static FtHal<Ft4232h, Initialized>, pin: Pin) -> FtOutPin<'static, Ft4232h> {
+    match pin {
+        Pin::AD0 => ftdi.ad0(),
+        Pin::AD1 => ftdi.ad1(),
+        Pin::AD2 => ftdi.ad2(),
+        Pin::AD3 => ftdi.ad3(),
+        Pin::AD4 => ftdi.ad4(),
+        Pin::AD5 => ftdi.ad5(),
+        Pin::AD6 => ftdi.ad6(),
+        Pin::AD7 => ftdi.ad7(),
+    }
+}
+
+// SPI controller and the BUSY, LDAC, RESET and CLR pins of an opened port.
+type Bus = (Box<dyn Transactional>, [Box<dyn IOController>; 4]);
+
+pub struct DeviceBuilder {
+    selector: FtdiSelector,
+    channel: Option<FtdiChannel>,
+    backend: Option<Backend>,
+    spidev: Option<SpidevBus>,
+    pins: PinMap,
+    mpsse: MpsseSettings,
+    spi: SpiConfig,
+    chip: Chip,
+    vref: f64,
+    cfg: Option<DriverConfig>,
+}
+
+/// MPSSE handle of a hal bus, boxed so that the pins can borrow it for `'static`.
+///
+/// Dropping it frees the handle and closes the port. `AD5370` holds it as its last field, so it
+/// goes after the pins and the SPI controller that borrow from it.
+pub(crate) struct HalHandle(*mut FtHal<Ft4232h, Initialized>);
+
+// Only the borrowing controllers use the handle, and they are `Send + Sync` themselves.
+unsafe impl Send for HalHandle {}
+unsafe impl Sync for HalHandle {}
+
+impl HalHandle {
+    fn leak(ftdi: FtHal<Ft4232h, Initialized>) -> (Self, &'static FtHal<Ft4232h, Initialized>) {
+        let ptr = Box::into_raw(Box::new(ftdi));
+        // Only `drop` frees the box, after every borrow is gone.
+        (Self(ptr), unsafe { &*ptr })
+    }
+}
+
+impl Drop for HalHandle {
+    fn drop(&mut self) {
+        unsafe { drop(Box::from_raw(self.0)) };
+    }
+}
+
+impl DeviceBuilder {
+    /// Channel A of the board with serial number `serial`, unless `channel` picks another.
+    pub fn serial(serial: &str) -> Self {
+        Self::new(FtdiSelector::Serial(serial.to_string()))
+    }
+
+    /// Device `index` of the D2XX device list.
+    pub fn index(index: i32) -> Self {
+        Self::new(FtdiSelector::Index(index))
+    }
+
+    /// A board on a Linux SPI controller. Only the SPI, chip, vref and driver settings apply.
+    pub fn spidev(bus: SpidevBus) -> Self {
+        Self {
+            spidev: Some(bus),
+            ..Self::index(0)
+        }
+    }
+
+    pub fn new(selector: FtdiSelector) -> Self {
+        Self {
+            selector,
+            channel: None,
+            backend: None,
+            spidev: None,
+            pins: PinMap::default(),
+            mpsse: MpsseSettings {
+                reset: true,
+                in_transfer_size: 4096,
+                read_timeout: Duration::from_secs(1),
+                write_timeout: Duration::from_secs(1),
+                latency_timer: Duration::from_millis(16),
+                mask: 0,
+                clock_frequency: None,
+            },
+            spi: SpiConfig::default(),
+            chip: Chip::AD5370,
+            vref: 4.0,
+            cfg: None,
+        }
+    }
+
+    /// Channel of a board opened by serial number. A device index already selects the channel,
+    /// so `build` rejects the combination.
+    pub fn channel(mut self, channel: FtdiChannel) -> Self {
+        self.channel = Some(channel);
+        self
+    }
+
+    /// Without it the hal backend is used, or mpsse when the driver config sets `verify`.
+    pub fn backend(mut self, backend: Backend) -> Self {
+        self.backend = Some(backend);
+        self
+    }
+
+    fn selected_backend(&self) -> Backend {
+        self.backend
+            .unwrap_or_else(|| Backend::for_verify(matches!(self.cfg, Some(cfg) if cfg.verify)))
+    }
+
+    pub fn pins(mut self, pins: PinMap) -> Self {
+        self.pins = pins;
+        self
+    }
+
+    /// USB and MPSSE settings. A clock frequency given here overrides the SPI clock.
+    pub fn mpsse(mut self, settings: MpsseSettings) -> Self {
+        if let Some(hz) = settings.clock_frequency {
+            self.spi.clock_hz = hz;
+        }
+        self.mpsse = settings;
+        self
+    }
+
+    /// Mode, clock and CS timing of the bus, checked against the AD537x timing table.
+    pub fn spi(mut self, cfg: SpiConfig) -> Self {
+        self.spi = cfg;
+        self
+    }
+
+    /// SCLK frequency, readback limits the AD537x to 20 MHz.
+    pub fn clock_frequency(mut self, hz: u32) -> Self {
+        self.spi.clock_hz = hz;
+        self
+    }
+
+    /// Read and write timeout of the USB transfers.
+    pub fn usb_timeout(mut self, timeout: Duration) -> Self {
+        self.mpsse.read_timeout = timeout;
+        self.mpsse.write_timeout = timeout;
+        self
+    }
+
+    pub fn chip(mut self, chip: Chip) -> Self {
+        self.chip = chip;
+        self
+    }
+
+    pub fn vref(mut self, vref: f64) -> Self {
+        self.vref = vref;
+        self
+    }
+
+    /// Driver settings. Without them the defaults apply, except that BUSY is not polled on the
+    /// hal backend, which can not read it.
+    pub fn config(mut self, cfg: DriverConfig) -> Self {
+        self.cfg = Some(cfg);
+        self
+    }
+
+    fn driver_config(&self) -> Result<DriverConfig, IError> {
+        let busy_readable = self.spidev.is_some() || self.selected_backend() == Backend::Mpsse;
+        match self.cfg {
+            Some(cfg) if cfg.busy_timeout.is_some() && !busy_readable => Err(IError::General {
+                msg: "busy_timeout needs the mpsse backend or spidev to read BUSY",
+            }),
+            Some(cfg) => Ok(cfg),
+            None if busy_readable => Ok(DriverConfig::default()),
+            None => Ok(DriverConfig {
+                busy_timeout: None,
+                ..DriverConfig::default()
+            }),
+        }
+    }
+
+    fn open(&self) -> Result<Ft4232h, IError> {
+        match &self.selector {
+            FtdiSelector::Serial(serial) => {
+                let channel = self.channel.unwrap_or(FtdiChannel::A);
+                if matches!(channel, FtdiChannel::C | FtdiChannel::D) {
+                    return Err(IError::General {
+                        msg: "only channels A and B of the FT4232H support MPSSE",
+                    });
+                }
+                let serial = format!("{}{}", serial, channel.suffix());
+                Ft4232h::with_serial_number(&serial).map_err(|_| IError::DeviceNotFound {
+                    what: "ftdi device",
+                })
+            }
+            FtdiSelector::Index(_) if self.channel.is_some() => Err(IError::General {
+                msg: "a device index already selects the channel",
+            }),
+            FtdiSelector::Index(index) => {
+                let ftdi = Ftdi::with_index(*index).map_err(|_| IError::DeviceNotFound {
+                    what: "ftdi device",
+                })?;
+                Ft4232h::try_from(ftdi).map_err(|_| IError::General {
+                    msg: "ftdi device is not an FT4232H",
+                })
+            }
+        }
+    }
+
+    fn hal_bus(&self, ft: Ft4232h) -> Result<(Bus, HalHandle), IError> {
+        let ftdi = hal::Ft4232hHal::with_ft(ft)
+            .init(&self.mpsse)
+            .map_err(|_| IError::Bus {
+                source: "mpsse init",
+            })?;
+        let (handle, ftdi) = HalHandle::leak(ftdi);
+        // On an error the controllers created so far are dropped before the handle.
+        Ok((self.hal_controllers(ftdi)?, handle))
+    }
+
+    fn hal_controllers(&self, ftdi: &'static FtHal<Ft4232h, Initialized>) -> Result<Bus, IError> {
+        let _spi = ftdi.spi().map_err(|_| IError::Bus { source: "ftdi spi" })?;
+        let spi = FtdiSPIController::new(_spi, ft_pin(ftdi, self.pins.cs), self.spi)?;
+        let pin =
+            |pin| -> Box<dyn IOController> { FtdiGPIOController::new_boxed(ft_pin(ftdi, pin)) };
+        let p = &self.pins;
+        Ok((
+            Box::new(spi),
+            [pin(p.busy), pin(p.ldac), pin(p.reset), pin(p.clr)],
+        ))
+    }
+
+    fn mpsse_bus(&self, mut ft: Ft4232h) -> Result<Bus, IError> {
+        ft.initialize_mpsse(&self.mpsse)?;
+        let p = &self.pins;
+        let bus = MpsseBus::new(ft, self.spi, p.cs, &[p.ldac, p.reset, p.clr])?.shared();
+        let pin =
+            |pin| -> Box<dyn IOController> { MpsseGPIOController::new_boxed(bus.clone(), pin) };
+        Ok((
+            Box::new(MpsseSPIController::new(bus.clone())),
+            [pin(p.busy), pin(p.ldac), pin(p.reset), pin(p.clr)],
+        ))
+    }
+
+    #[cfg(feature = "linux")]
+    fn spidev_bus(&self, bus: &SpidevBus) -> Result<Bus, IError> {
+        use crate::interface::linux;
+
+        let spi = linux::open_spidev(&bus.spidev, self.spi)?;
+        let mut chip = linux::open_chip(&bus.gpiochip)?;
+        let mut pin = |offset, output| -> Result<Box<dyn IOController>, IError> {
+            Ok(Box::new(linux::open_line(&mut chip, offset, output)?))
+        };
+        Ok((
+            Box::new(spi),
+            [
+                pin(bus.busy, false)?,
+                pin(bus.ldac, true)?,
+                pin(bus.reset, true)?,
+                pin(bus.clr, true)?,
+            ],
+        ))
+    }
+
+    #[cfg(not(feature = "linux"))]
+    fn spidev_bus(&self, _bus: &SpidevBus) -> Result<Bus, IError> {
+        Err(IError::General {
+            msg: "spidev needs the linux feature",
+        })
+    }
+
+    /// Opens the device and runs `AD5370::init` on it. The port is closed again when `init` fails
+    /// or the device is dropped, so the board can be reopened.
+    pub fn build(mut self) -> Result<AD5370<'static>, IError> {
+        timing::AD537X.check(&self.spi)?;
+        let cfg = self.driver_config()?;
+        let mut port = None;
+        let bus = match &self.spidev {
+            Some(bus) => self.spidev_bus(bus)?,
+            None => {
+                self.pins.check()?;
+                self.spi.check_ftdi_mode()?;
+                self.mpsse.clock_frequency = Some(self.spi.clock_hz);
+                let ft = self.open()?;
+                match self.selected_backend() {
+                    Backend::Hal => {
+                        let (bus, handle) = self.hal_bus(ft)?;
+                        port = Some(handle);
+                        bus
+                    }
+                    Backend::Mpsse => self.mpsse_bus(ft)?,
+                }
+            }
+        };
+        // A device that fails `init` is dropped inside `finish`, before `port` closes.
+        let mut dev = self.finish(cfg, bus)?;
+        dev._port = port;
+        Ok(dev)
+    }
+
+    fn finish(&self, cfg: DriverConfig, bus: Bus) -> Result<AD5370<'static>, IError> {
+        let (spi, pins) = bus;
+        let mut dev = AD5370::new(self.chip, self.vref, cfg, spi, pins);
+        dev.init()?;
+        Ok(dev)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{
+        reg::ChannelAddress,
+        sim::{SimPin, Simulator},
+    };
+    use super::*;
+
+    // Pin of the hal backend, which can be driven but not read.
+    struct OutputOnly(Box<dyn IOController>);
+
+    impl IOController for OutputOnly {
+        fn set(&mut self) -> Result<(), IError> {
+            self.0.set()
+        }
+
+        fn reset(&mut self) -> Result<(), IError> {
+            self.0.reset()
+        }
+
+        fn read(&mut self) -> Result<bool, IError> {
+            Err(IError::General {
+                msg: "ftdi gpio pins can not be read",
+            })
+        }
+    }
+
+    fn hal_bus(sim: &Simulator) -> Bus {
+        let pin = |pin| -> Box<dyn IOController> { Box::new(OutputOnly(Box::new(sim.pin(pin)))) };
+        (
+            Box::new(sim.spi()),
+            [
+                pin(SimPin::Busy),
+                pin(SimPin::Ldac),
+                pin(SimPin::Reset),
+                pin(SimPin::Clr),
+            ],
+        )
+    }
+
+    #[test]
+    fn test_default_config() {
+        // BUSY can not be read on the hal backend, so the defaults must not wait for it.
+        let builder = DeviceBuilder::serial("FT1234");
+        let cfg = builder.driver_config().unwrap();
+        assert_eq!(cfg.busy_timeout, None);
+        // Nor pulse RESET, which the EVAL board does not wire.
+        assert!(!cfg.hw_reset);
+        let sim = Simulator::new();
+        sim.set_busy_reads(3);
+        let mut dev = builder.finish(cfg, hal_bus(&sim)).unwrap();
+        dev.set_code(0x1234, ChannelAddress::AllCh).unwrap();
+        assert_eq!(sim.registers().x1_a, [0x1234; 40]);
+
+        let mpsse = DeviceBuilder::serial("FT1234").backend(Backend::Mpsse);
+        assert!(mpsse.driver_config().unwrap().busy_timeout.is_some());
+        // Verified writes read back through mpsse unless another backend is asked for.
+        let verify = DriverConfig {
+            verify: true,
+            ..DriverConfig::default()
+        };
+        let builder = DeviceBuilder::serial("FT1234").config(verify);
+        assert_eq!(builder.selected_backend(), Backend::Mpsse);
+        assert!(builder.driver_config().is_ok());
+        let hal = DeviceBuilder::serial("FT1234")
+            .backend(Backend::Hal)
+            .config(verify);
+        assert_eq!(hal.selected_backend(), Backend::Hal);
+        // An explicit BUSY timeout on the hal backend is rejected before the device is opened.
+        let res = DeviceBuilder::serial("FT1234")
+            .config(DriverConfig::default())
+            .build();
+        assert!(matches!(res, Err(IError::General { msg }) if msg.contains("busy_timeout")));
+    }
+
+    #[test]
+    fn test_index_channel() {
+        let res = DeviceBuilder::index(0).channel(FtdiChannel::B).open();
+        assert!(matches!(res, Err(IError::General { msg }) if msg.contains("index")));
+    }
+
+    #[test]
+    fn test_pin_map() {
+        assert!(PinMap::default().check().is_ok());
+        let spi_pin = PinMap {
+            cs: Pin::AD1,
+            ..PinMap::default()
+        };
+        assert!(spi_pin.check().is_err());
+        let twice = PinMap {
+            clr: Pin::AD5,
+            ..PinMap::default()
+        };
+        assert!(twice.check().is_err());
+        let res = DeviceBuilder::serial("FT1234")
+            .channel(FtdiChannel::C)
+            .build();
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_group_boards() {
+        let info = |serial: &str, device_type, port_open| DeviceInfo {
+            port_open,
+            device_type,
+            vendor_id: 0x0403,
+            product_id: 0x6011,
+            serial_number: serial.to_string(),
+            description: format!("Quad RS232-HS {}", serial.chars().last().unwrap()),
+        };
+        let devices = [
+            info("FT5RA1A", DeviceType::FT4232H, true),
+            info("FT5RA1B", DeviceType::FT4232H, false),
+            info("FT9ZZ0A", DeviceType::FT232H, false),
+            info("FT5RB7A", DeviceType::FT4232H, false),
+            info("FT5RA1C", DeviceType::FT4232H, true),
+            info("FT5RB7B", DeviceType::FT4232H, false),
+        ];
+        let boards = group_boards(&devices);
+        assert_eq!(boards.len(), 2);
+        assert_eq!(boards[0].serial, "FT5RA1");
+        assert_eq!(boards[0].description, "Quad RS232-HS A");
+        assert_eq!(boards[0].open, vec![FtdiChannel::A, FtdiChannel::C]);
+        assert_eq!(boards[1].serial, "FT5RB7");
+        assert!(boards[1].open.is_empty());
+    }
+}
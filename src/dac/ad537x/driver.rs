@@ -2,10 +2,19 @@
 
 use super::{
     builder::*,
-    reg::{ReadBackAddr, Register},
+    chip::Chip,
+    device::HalHandle,
+    reg::{ABSelect, Channel, Control, OffsetDac, ReadBackAddr, Register, SpecialFunctionAddress},
+    timing, transfer,
+    verify::VerifyStats,
     ReadResp,
 };
 
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
 use crate::{
     error::IError,
     interface::{gpio::IOController, spi::Transactional},
@@ -13,8 +22,47 @@ use crate::{
 
 use super::reg::{ChannelAddress, WriteMode};
 
+/// Driver behaviour that depends on how the board is wired.
+#[derive(Clone, Copy, Debug)]
+pub struct DriverConfig {
+    /// How long to wait for BUSY to be released before the next write or LDAC pulse.
+    /// `None` skips the wait, for boards where BUSY can not be read back.
+    pub busy_timeout: Option<Duration>,
+    /// Whether RESET is wired, so that `init` can pulse it and check the power-on defaults.
+    /// Off by default, the EVAL board leaves RESET unconnected.
+    pub hw_reset: bool,
+    /// Whether every gain, offset, data and control write is read back and compared.
+    ///
+    /// A readback clocks the data frame out while SDO is sampled. The mpsse backend does that in
+    /// one USB transfer and is picked by `DeviceBuilder` when `verify` is set and no backend is
+    /// given. On the hal backend it depends on the `Transfer` impl of ftdi-embedded-hal, which
+    /// may split the frame and read back wrong values.
+    pub verify: bool,
+    /// How often a write that reads back wrong is repeated before it fails.
+    pub verify_retries: u8,
+}
+
+impl Default for DriverConfig {
+    fn default() -> Self {
+        Self {
+            busy_timeout: Some(Duration::from_millis(10)),
+            hw_reset: false,
+            verify: false,
+            verify_retries: 2,
+        }
+    }
+}
+
+// Minimum RESET low time is in the tens of nanoseconds, a microsecond leaves plenty of margin.
+const RESET_PULSE: Duration = Duration::from_micros(1);
+// Upper bound of the reset sequence, used when BUSY can not be polled.
+const RESET_TIME: Duration = Duration::from_millis(1);
+
+/// Driver for the AD537x family, `chip` describes the part on the board.
 pub struct AD5370<'a> {
+    pub chip: Chip,
     pub vref: f64,
+    pub cfg: DriverConfig,
     pub reg: Register,
     pub spi: Box<dyn Transactional + 'a>,
     ///BUSY Input/Output (Active Low). BUSY is open-drain when an output.
@@ -27,9 +75,39 @@ pub struct AD5370<'a> {
     ///Asynchronous Clear Input (Level Sensitive, Active Low).
     ///See the Clear Function section for more information
     pub _clr: Box<dyn IOController + 'a>,
+    /// Outcome of the readback checks made with `cfg.verify` set.
+    pub stats: VerifyStats,
+    /// Port the controllers above borrow from on the hal backend. Declared last so that it is
+    /// closed after them.
+    pub(crate) _port: Option<HalHandle>,
 }
 
 impl<'a> AD5370<'a> {
+    /// Driver on `spi` with the control pins BUSY, LDAC, RESET and CLR, in that order. The
+    /// shadow registers start at the power-on defaults, `init` brings the chip there.
+    pub fn new(
+        chip: Chip,
+        vref: f64,
+        cfg: DriverConfig,
+        spi: Box<dyn Transactional + 'a>,
+        pins: [Box<dyn IOController + 'a>; 4],
+    ) -> Self {
+        let [_busy, _ldac, _reset, _clr] = pins;
+        Self {
+            chip,
+            vref,
+            cfg,
+            reg: Register::for_chip(&chip),
+            spi,
+            _busy,
+            _ldac,
+            _reset,
+            _clr,
+            stats: VerifyStats::default(),
+            _port: None,
+        }
+    }
+
     pub fn get_reg(&self) -> Register {
         self.reg
     }
@@ -52,135 +130,394 @@ impl<'a> AD5370<'a> {
         Ok(())
     }
 
+    /// Brings the chip into a known state.
+    ///
+    /// With `cfg.hw_reset` set RESET is pulsed low, the reset sequence is awaited on BUSY and the
+    /// power-on defaults are verified by readback before the shadow registers are reset.
+    /// CLR and LDAC are released in either case. Fails before touching the chip if the SPI
+    /// settings violate its timing.
     pub fn init(&mut self) -> Result<(), IError> {
-        //TODO: use button on the EVAL board for now. please keep LK3 connected.
-        // self._reset.reset();
-        // sleep(Duration::from_millis(1));
-        // self._reset.set()?;
+        self.check_spi()?;
+        if self.cfg.hw_reset {
+            self._reset.reset()?;
+            thread::sleep(RESET_PULSE);
+            self._reset.set()?;
+            match self.cfg.busy_timeout {
+                Some(t) => self.wait_busy_for(t.max(RESET_TIME))?,
+                None => thread::sleep(RESET_TIME),
+            }
+        }
 
         self._clr.set()?;
         self._ldac.set()?;
+
+        if self.cfg.hw_reset {
+            let defaults = Register::for_chip(&self.chip);
+            let checks = [
+                (ReadBackAddr::Control, defaults.control as u16),
+                (ReadBackAddr::OFS0, defaults.ofs0),
+                (ReadBackAddr::OFS1, defaults.ofs1),
+                (ReadBackAddr::X1A { group: 0, ch: 0 }, defaults.x1_a[0]),
+                (ReadBackAddr::M { group: 0, ch: 0 }, defaults.gain[0]),
+                (ReadBackAddr::C { group: 0, ch: 0 }, defaults.offset[0]),
+            ];
+            for (addr, expected) in checks.iter() {
+                let actual = self.read_register(*addr)?;
+                if actual != *expected {
+                    return Err(IError::ReadbackMismatch {
+                        expected: *expected,
+                        actual,
+                    });
+                }
+            }
+            self.reg = defaults;
+        }
         Ok(())
     }
+
+    /// Checks the bus settings of `spi` against the AD537x timing table.
+    pub fn check_spi(&self) -> Result<(), IError> {
+        match self.spi.spi_config() {
+            Some(cfg) => timing::AD537X.check(&cfg),
+            None => Ok(()),
+        }
+    }
+
+    /// Waits until BUSY is released, i.e. the chip has finished processing the previous frame.
+    pub fn wait_busy(&mut self) -> Result<(), IError> {
+        match self.cfg.busy_timeout {
+            Some(t) => self.wait_busy_for(t),
+            None => Ok(()),
+        }
+    }
+
+    fn wait_busy_for(&mut self, timeout: Duration) -> Result<(), IError> {
+        let start = Instant::now();
+        while !self._busy.read()? {
+            if start.elapsed() > timeout {
+                return Err(IError::BusyTimeout {
+                    waited: start.elapsed(),
+                });
+            }
+            thread::yield_now();
+        }
+        Ok(())
+    }
+
     pub fn write_raw(&mut self, data: [u8; 3]) -> Result<(), IError> {
+        self.wait_busy()?;
         self.spi.spi_write(&data)?;
         Ok(())
     }
 
-    fn voltage_to_input(&self, vol: f64, group: u8, ch: u8) -> u16 {
-        let vs = 0.0;
-        let k1 = 1_u32 << 16_u32;
-        let k2 = 1_u16 << 15_u16;
-        let ofs: u16 = match group {
-            0 => self.reg.ofs0,
-            _ => self.reg.ofs1,
-        };
-        let idx = (group * 8 + ch) as usize;
-        let c = self.reg.offset[idx];
-        let m = self.reg.gain[idx];
+    /// Streams `frames` to the chip in as few bus transfers as the backend allows.
+    /// BUSY is only checked before the first frame.
+    pub fn write_frames(&mut self, frames: &[[u8; 3]]) -> Result<(), IError> {
+        self.wait_busy()?;
+        let frames: Vec<&[u8]> = frames.iter().map(|f| &f[..]).collect();
+        self.spi.spi_write_frames(&frames)
+    }
 
-        let first_item = (vol - vs) * (k1 as f64) / (4.0 * self.vref);
-        let suffix = (4 * ofs + k2 - c) as f64;
-        let coef = (k1 as f64/ (m + 1) as f64) as f64;
+    fn ofs(&self, group: u8) -> u16 {
+        self.reg.ofs(self.chip.ofs_of(group))
+    }
+
+    /// Input code (X1) that makes channel `ch` of `group` output `vol`, using the cached trims.
+    pub fn voltage_to_input(&self, vol: f64, group: u8, ch: u8) -> Result<u16, IError> {
+        let idx = Channel::from_group_ch(group, ch)?
+            .check(&self.chip)?
+            .index();
+        let x2 = transfer::voltage_to_x2(&self.chip, vol, self.ofs(group), self.vref)?;
+        transfer::x2_to_x1(&self.chip, x2, self.reg.gain[idx], self.reg.offset[idx])
+    }
+
+    /// Output voltage channel `ch` of `group` settles to for input code `code`.
+    pub fn code_to_voltage(&self, code: u16, group: u8, ch: u8) -> Result<f64, IError> {
+        let idx = Channel::from_group_ch(group, ch)?
+            .check(&self.chip)?
+            .index();
+        let x2 = transfer::x1_to_x2(&self.chip, code, self.reg.gain[idx], self.reg.offset[idx]);
+        Ok(transfer::x2_to_voltage(
+            &self.chip,
+            x2,
+            self.ofs(group),
+            self.vref,
+        ))
+    }
+
+    /// Checks `target` and `value` and encodes the frame writing them.
+    pub(crate) fn frame(
+        &self,
+        mode: WriteMode,
+        target: ChannelAddress,
+        value: u16,
+    ) -> Result<[u8; 3], IError> {
+        target.check(&self.chip)?;
+        if value > self.chip.max_code() {
+            return Err(IError::OutOfRange {
+                what: "code",
+                value: value as f64,
+            });
+        }
+        Ok(MainBuilder::default()
+            .write(mode)
+            .address(target)
+            .data(self.chip.code_to_field(value))
+            .build())
+    }
 
-        ((first_item + suffix) * coef).round() as u16
+    /// Writes `value` to the registers selected by `mode` and `target`, keeping `reg` in sync.
+    fn write(&mut self, mode: WriteMode, target: ChannelAddress, value: u16) -> Result<(), IError> {
+        let data = self.frame(mode, target, value)?;
+        self.write_raw(data)?;
+        self.reg.apply_write(&self.chip, mode, target, value);
+        self.verify_write(&[data], &[(mode, target, value)])
     }
 
     pub fn set_code(&mut self, code: u16, target: ChannelAddress) -> Result<(), IError> {
+        self.write(WriteMode::Data, target, code)
+    }
+    /// Input code for `vol` on `target`. Multi-channel targets are converted with the
+    /// calibration of their first channel.
+    pub fn voltage_code(&self, vol: f64, target: ChannelAddress) -> Result<u16, IError> {
+        let idx = target.channels(&self.chip).next().unwrap_or(0);
+        self.voltage_to_input(vol, (idx / 8) as u8, (idx % 8) as u8)
+    }
+
+    pub fn set_voltage(&mut self, vol: f64, target: ChannelAddress) -> Result<(), IError> {
+        let code = self.voltage_code(vol, target)?;
+        self.write(WriteMode::Data, target, code)
+    }
+
+    /// Writes the gain trim (M) register of every channel addressed by `target`.
+    pub fn set_gain(&mut self, value: u16, target: ChannelAddress) -> Result<(), IError> {
+        self.write(WriteMode::Gain, target, value)
+    }
+
+    /// Writes the offset trim (C) register of every channel addressed by `target`.
+    pub fn set_offset(&mut self, value: u16, target: ChannelAddress) -> Result<(), IError> {
+        self.write(WriteMode::Offset, target, value)
+    }
+
+    /// Programs per-channel M and C registers from a calibration table indexed by group * 8 + ch.
+    /// Entries past the channel count of the chip are ignored.
+    pub fn apply_trims(&mut self, gain: &[u16; 40], offset: &[u16; 40]) -> Result<(), IError> {
+        for idx in 0..self.chip.channels() {
+            let target = ChannelAddress::SingleCh {
+                ch: (idx % 8) as u8,
+                group: (idx / 8) as u8,
+            };
+            self.set_gain(gain[idx], target)?;
+            self.set_offset(offset[idx], target)?;
+        }
+        Ok(())
+    }
+
+    /// Programs one of the 14-bit offset DACs, shifting the output span of its groups.
+    pub fn set_offset_dac(&mut self, dac: OffsetDac, code: u16) -> Result<(), IError> {
+        if code > 0x3FFF {
+            return Err(IError::OutOfRange {
+                what: "offset dac code",
+                value: code as f64,
+            });
+        }
+        if !self.chip.offset_dacs().contains(&dac) {
+            return Err(IError::General {
+                msg: "offset dac not present on this chip",
+            });
+        }
         let data = MainBuilder::default()
-            .write(WriteMode::Data)
-            .address(target)
+            .funtion()
+            .address(dac.into())
             .data(code)
             .build();
-        
-        //11_00 0000_
-        // let data =[
-        //     0b1100_0000,
-        //     (code>>8) as u8,
-        //     code as u8
-        // ];
-        
+        self.write_raw(data)?;
+        self.reg.set_ofs(dac, code);
+        self.verify(&[data], &[(dac.into(), code)])
+    }
 
-        self.spi.spi_write(&data)?;
-        Ok(())
+    /// Moves the output span of all channels to `min_v..max_v` and returns the OFS code used.
+    ///
+    /// VOUT = 4 * VREF * (DAC_CODE - 4 * OFS) / 2^16 on the 16-bit parts, so the width of the span
+    /// is fixed at 4 * VREF and the offset DACs only shift it. One OFS step is 4 * VREF / 2^14
+    /// on every part.
+    pub fn set_output_span(&mut self, min_v: f64, max_v: f64) -> Result<u16, IError> {
+        let span = 4.0 * self.vref;
+        let step = span / 16384.0;
+        if ((max_v - min_v) - span).abs() > step {
+            return Err(IError::OutOfRange {
+                what: "output span width",
+                value: max_v - min_v,
+            });
+        }
+        let code = (-min_v / step).round();
+        if !(0.0..=16383.0).contains(&code) {
+            return Err(IError::OutOfRange {
+                what: "output span minimum",
+                value: min_v,
+            });
+        }
+        for dac in self.chip.offset_dacs() {
+            self.set_offset_dac(*dac, code as u16)?;
+        }
+        Ok(code as u16)
     }
-    pub fn set_voltage(&mut self, vol: f64, target: ChannelAddress) -> Result<(), IError> {
-        let (g, c) = match target {
-            ChannelAddress::AllCh => (0, 0),
-            ChannelAddress::SingleCh { ch, group } => (group, ch),
-            ChannelAddress::SingleGroup { group } => (group, 0),
-            ChannelAddress::Chx { ch } => (0, ch),
-            ChannelAddress::ChxExceptGroup0 { ch } => (1, ch),
+
+    /// Read-modify-write of the control register bits in `mask` against the shadow copy.
+    fn update_control(&mut self, mask: u8, set: bool) -> Result<Control, IError> {
+        let value = if set {
+            self.reg.control | mask
+        } else {
+            self.reg.control & !mask
         };
         let data = MainBuilder::default()
-            .write(WriteMode::Data)
-            .address(target)
-            .data(self.voltage_to_input(vol, g, c))
+            .funtion()
+            .address(SpecialFunctionAddress::WriteControl)
+            .data(value as u16)
             .build();
+        self.write_raw(data)?;
+        self.reg.control = value;
+        self.verify(&[data], &[(ReadBackAddr::Control, value as u16)])?;
+        Ok(value.into())
+    }
 
-        println!("set voltage: write data {:?}", data);
-        self.spi.spi_write(&data)?;
+    /// Chooses whether subsequent data writes go to X1A or X1B.
+    pub fn select_input_register(&mut self, sel: ABSelect) -> Result<Control, IError> {
+        self.update_control(Control::AB_SELECT, sel == ABSelect::B)
+    }
+
+    pub fn set_thermal_shutdown(&mut self, enable: bool) -> Result<Control, IError> {
+        self.update_control(Control::THERMAL_SHUTDOWN, enable)
+    }
+
+    /// Soft power-down puts every output into a high impedance state; register contents are kept.
+    pub fn set_power_down(&mut self, power_down: bool) -> Result<Control, IError> {
+        self.update_control(Control::POWER_DOWN, power_down)
+    }
+
+    /// Loads the DAC registers from X2A/X2B on the falling edge of LDAC.
+    pub fn pulse_ldac(&mut self) -> Result<(), IError> {
+        self.wait_busy()?;
+        self._ldac.reset()?;
+        self._ldac.set()?;
         Ok(())
     }
 
-    pub fn set_gain(&mut self, value: u16) -> Result<(), IError> {
+    /// Writes `a` to X1A and `b` to X1B of every channel in `target`.
+    /// The A/B bit of the control register is restored afterwards.
+    pub fn preload(&mut self, target: ChannelAddress, a: u16, b: u16) -> Result<(), IError> {
+        let prev = Control::from(self.reg.control).ab_select;
+        self.select_input_register(ABSelect::A)?;
+        self.set_code(a, target)?;
+        self.select_input_register(ABSelect::B)?;
+        self.set_code(b, target)?;
+        self.select_input_register(prev)?;
+        Ok(())
+    }
+
+    /// Writes the A/B select register of `group`. Bit n set makes channel n output X2B.
+    pub fn set_select(&mut self, group: u8, mask: u8) -> Result<(), IError> {
+        if group >= self.chip.groups {
+            return Err(IError::OutOfRange {
+                what: "group",
+                value: group as f64,
+            });
+        }
         let data = MainBuilder::default()
-            .write(WriteMode::Gain)
-            .address(ChannelAddress::AllCh)
-            .data(value)
+            .funtion()
+            .address(SpecialFunctionAddress::WriteSelect { group })
+            .data(mask as u16)
             .build();
-        self.spi.spi_write(&data)
+        self.write_raw(data)?;
+        self.reg.select[group as usize] = mask;
+        self.verify(&[data], &[(ReadBackAddr::Select { group }, mask as u16)])
     }
 
-    pub fn set_offset(&mut self, value: u16) -> Result<(), IError> {
+    /// Writes the same A/B select mask to every group in a single frame.
+    pub fn set_select_all(&mut self, mask: u8) -> Result<(), IError> {
         let data = MainBuilder::default()
-            .write(WriteMode::Offset)
-            .address(ChannelAddress::AllCh)
-            .data(value)
+            .funtion()
+            .address(SpecialFunctionAddress::WriteSelectAll)
+            .data(mask as u16)
             .build();
-        self.spi.spi_write(&data)
+        self.write_raw(data)?;
+        for group in 0..self.chip.groups {
+            self.reg.select[group as usize] = mask;
+        }
+        let checks: Vec<_> = (0..self.chip.groups)
+            .map(|group| (ReadBackAddr::Select { group }, mask as u16))
+            .collect();
+        self.verify(&[data], &checks)
     }
 
-    #[allow(dead_code)]
-    pub fn read_all(&mut self) -> Result<(), IError> {
-        let mut builder = MainBuilder::default();
-        for group in 0..5 {
-            for ch in 0..8 {
-                let prefix: [[u8; 3]; 4] = [
-                    builder.read(ReadBackAddr::X1A { group, ch }).build(),
-                    builder.read(ReadBackAddr::X1B { group, ch }).build(),
-                    builder.read(ReadBackAddr::C { group, ch }).build(),
-                    builder.read(ReadBackAddr::M { group, ch }).build(),
-                ];
-                let mut data: [ReadResp; 4] = [ReadResp::new(); 4];
-                let name = ["X1A", "X1B", "C", "M"];
-                for item in 0..4 {
-                    self.spi.spi_read(&prefix[item], data[item].as_mut())?;
-                    println!(
-                        "read reg (group:{},ch:{}){} , value:{:}",
-                        group, ch, name[item], &data[item]
-                    );
-                }
-                self.reg.x1_a[ch as usize] = data[0].to_u16();
-                self.reg.x1_b[ch as usize] = data[1].to_u16();
-                self.reg.offset[ch as usize] = data[2].to_u16();
-                self.reg.gain[ch as usize] = data[3].to_u16();
-            }
-        }
+    /// Switches the channels of `group` to the A/B set in `mask` and updates their outputs at once.
+    pub fn toggle_group(&mut self, group: u8, mask: u8) -> Result<(), IError> {
+        self.set_select(group, mask)?;
+        self.pulse_ldac()
+    }
 
-        let mut data = ReadResp::new();
+    /// Switches every channel to the A or B set and updates all outputs at once.
+    pub fn toggle_all(&mut self, sel: ABSelect) -> Result<(), IError> {
+        self.set_select_all(match sel {
+            ABSelect::A => 0x00,
+            ABSelect::B => 0xFF,
+        })?;
+        self.pulse_ldac()
+    }
 
-        let mut prefix: [u8; 3] = builder.read(ReadBackAddr::OFS0).build();
+    /// Issues a readback request for `addr` and returns the register value shifted out on SDO
+    /// while a NOP is clocked in. Waits for BUSY first, so a preceding write has been processed.
+    pub fn read_register(&mut self, addr: ReadBackAddr) -> Result<u16, IError> {
+        addr.check(&self.chip)?;
+        let prefix = MainBuilder::default().read(addr).build();
+        let mut data = ReadResp::nop();
+        self.wait_busy()?;
         self.spi.spi_read(&prefix, data.as_mut())?;
-        self.reg.ofs0 = data.to_u16();
-        println!("read reg ofs0, value:{:}", &data);
+        if addr.is_code() {
+            return Ok(self.chip.field_to_code(data.to_u16()));
+        }
+        Ok(data.to_u16())
+    }
 
-        prefix = (*builder.read(ReadBackAddr::OFS1)).build();
-        self.spi.spi_read(&prefix, data.as_mut())?;
-        self.reg.ofs1 = data.to_u16();
-        println!("read reg ofs1, value:{:}", &data);
+    /// Reads back every register of the chip and refreshes the `reg` cache with the result.
+    pub fn snapshot(&mut self) -> Result<Register, IError> {
+        let mut reg = self.reg;
+        for group in 0..self.chip.groups {
+            for ch in 0..8 {
+                let idx = (group * 8 + ch) as usize;
+                reg.x1_a[idx] = self.read_register(ReadBackAddr::X1A { group, ch })?;
+                reg.x1_b[idx] = self.read_register(ReadBackAddr::X1B { group, ch })?;
+                reg.offset[idx] = self.read_register(ReadBackAddr::C { group, ch })?;
+                reg.gain[idx] = self.read_register(ReadBackAddr::M { group, ch })?;
+            }
+            reg.select[group as usize] = self.read_register(ReadBackAddr::Select { group })? as u8;
+        }
+        reg.control = self.read_register(ReadBackAddr::Control)? as u8;
+        for dac in self.chip.offset_dacs() {
+            reg.set_ofs(*dac, self.read_register((*dac).into())?);
+        }
+        self.reg = reg;
+        Ok(reg)
+    }
 
-        self.spi.spi_read(&prefix, data.as_mut())?;
+    #[allow(dead_code)]
+    pub fn read_all(&mut self) -> Result<(), IError> {
+        let reg = self.snapshot()?;
+        for idx in 0..self.chip.channels() {
+            println!(
+                "read reg (group:{},ch:{}) X1A:0x{:04X} X1B:0x{:04X} C:0x{:04X} M:0x{:04X}",
+                idx / 8,
+                idx % 8,
+                reg.x1_a[idx],
+                reg.x1_b[idx],
+                reg.offset[idx],
+                reg.gain[idx]
+            );
+        }
+        println!(
+            "read reg ofs0:0x{:04X} ofs1:0x{:04X} ofs2:0x{:04X} control:0x{:02X} select:{:02X?}",
+            reg.ofs0, reg.ofs1, reg.ofs2, reg.control, reg.select
+        );
         Ok(())
     }
 }
@@ -188,7 +525,34 @@ impl<'a> AD5370<'a> {
 #[allow(clippy::unusual_byte_groupings)]
 #[cfg(test)]
 mod test {
+    use std::{
+        convert::TryFrom,
+        sync::{Arc, Mutex},
+    };
+
+    use super::super::{
+        command::Command,
+        sim::{SimSPI, Simulator},
+    };
     use super::*;
+    use crate::interface::spi::SpiConfig;
+
+    // Hands everything to the simulator and keeps the data frames clocked out by `spi_read`.
+    struct Probe(SimSPI, Arc<Mutex<Vec<[u8; 3]>>>);
+
+    impl Transactional for Probe {
+        fn spi_read(&mut self, prefix: &[u8], data: &mut [u8]) -> Result<(), IError> {
+            self.1
+                .lock()
+                .unwrap()
+                .push(<[u8; 3]>::try_from(&*data).unwrap());
+            self.0.spi_read(prefix, data)
+        }
+
+        fn spi_write(&mut self, data: &[u8]) -> Result<(), IError> {
+            self.0.spi_write(data)
+        }
+    }
 
     #[test]
     fn test_builder() {
@@ -228,4 +592,333 @@ mod test {
             .build();
         assert_eq!([0b00_000101, 0b000_01101, 0b0000_0000], data);
     }
+
+    #[test]
+    fn test_snapshot() {
+        let sim = Simulator::new();
+        let mut dev = sim.driver(4.0);
+        dev.set_code(0x2000, ChannelAddress::SingleGroup { group: 1 })
+            .unwrap();
+        dev.set_code(0x3000, ChannelAddress::SingleCh { ch: 2, group: 4 })
+            .unwrap();
+        dev.set_offset(0x7000, ChannelAddress::AllCh).unwrap();
+
+        let reg = dev.snapshot().unwrap();
+        assert_eq!(reg, sim.registers());
+        assert_eq!(dev.reg, sim.registers());
+        assert_eq!(reg.x1_a[0], 0x5555);
+        assert_eq!(reg.x1_a[9], 0x2000);
+        assert_eq!(reg.x1_a[34], 0x3000);
+        assert_eq!(
+            dev.read_register(ReadBackAddr::C { group: 3, ch: 3 })
+                .unwrap(),
+            0x7000
+        );
+    }
+
+    #[test]
+    fn test_shadow_tracking() {
+        let sim = Simulator::new();
+        let mut dev = sim.driver(4.0);
+        dev.set_gain(0xF000, ChannelAddress::AllCh).unwrap();
+        dev.set_offset(0x8000, ChannelAddress::AllCh).unwrap();
+        dev.set_code(0x1000, ChannelAddress::ChxExceptGroup0 { ch: 6 })
+            .unwrap();
+        dev.set_code(0x2000, ChannelAddress::SingleGroup { group: 0 })
+            .unwrap();
+        assert_eq!(dev.reg, sim.registers());
+        assert_eq!(dev.reg.x1_a[6], 0x2000);
+        assert_eq!(dev.reg.x1_a[14], 0x1000);
+
+        // Conversion uses the trimmed gain rather than the power-on default.
+        let before = dev.voltage_to_input(1.0, 1, 0).unwrap();
+        dev.set_gain(0x7FFF, ChannelAddress::AllCh).unwrap();
+        assert_ne!(before, dev.voltage_to_input(1.0, 1, 0).unwrap());
+        assert_eq!(dev.reg, sim.registers());
+    }
+
+    #[test]
+    fn test_trims() {
+        let sim = Simulator::new();
+        let mut dev = sim.driver(4.0);
+        dev.set_gain(0xEEEE, ChannelAddress::SingleCh { ch: 1, group: 2 })
+            .unwrap();
+        dev.set_offset(0x7777, ChannelAddress::SingleGroup { group: 3 })
+            .unwrap();
+        assert_eq!(sim.registers().gain[17], 0xEEEE);
+        assert_eq!(sim.registers().gain[16], Register::default().gain[16]);
+        assert_eq!(sim.registers().offset[24..32], [0x7777; 8]);
+
+        let mut gain = [0_u16; 40];
+        let mut offset = [0_u16; 40];
+        for idx in 0..40 {
+            gain[idx] = 0xF000 + idx as u16;
+            offset[idx] = 0x8000 - idx as u16;
+        }
+        dev.apply_trims(&gain, &offset).unwrap();
+        assert_eq!(sim.registers().gain, gain);
+        assert_eq!(sim.registers().offset, offset);
+        assert_eq!(dev.reg, sim.registers());
+    }
+
+    #[test]
+    fn test_output_span() {
+        let sim = Simulator::new();
+        let mut dev = sim.driver(5.0);
+        assert_eq!(dev.set_output_span(-10.0, 10.0).unwrap(), 0x2000);
+        assert_eq!(dev.set_output_span(0.0, 20.0).unwrap(), 0);
+        assert_eq!(dev.set_output_span(-5.0, 15.0).unwrap(), 0x1000);
+        assert_eq!(sim.registers().ofs0, 0x1000);
+        assert_eq!(sim.registers().ofs1, 0x1000);
+
+        assert!(dev.set_output_span(-10.0, 5.0).is_err());
+        assert!(dev.set_output_span(1.0, 21.0).is_err());
+        assert!(dev.set_offset_dac(OffsetDac::OFS1, 0x4000).is_err());
+        dev.set_offset_dac(OffsetDac::OFS1, 0x1555).unwrap();
+        assert_eq!(sim.registers().ofs0, 0x1000);
+        assert_eq!(sim.registers().ofs1, 0x1555);
+        assert_eq!(dev.reg, sim.registers());
+    }
+
+    #[test]
+    fn test_control() {
+        let sim = Simulator::new();
+        let mut dev = sim.driver(4.0);
+        let ctrl = dev.set_thermal_shutdown(true).unwrap();
+        assert!(ctrl.thermal_shutdown && !ctrl.power_down);
+        assert_eq!(ctrl.ab_select, ABSelect::A);
+
+        let ctrl = dev.select_input_register(ABSelect::B).unwrap();
+        assert_eq!(ctrl.ab_select, ABSelect::B);
+        assert!(ctrl.thermal_shutdown);
+        dev.set_code(0x4321, ChannelAddress::AllCh).unwrap();
+        assert_eq!(sim.registers().x1_b, [0x4321; 40]);
+        assert_eq!(sim.registers().x1_a, Register::default().x1_a);
+
+        let ctrl = dev.set_power_down(true).unwrap();
+        assert_eq!(u8::from(ctrl), 0b111);
+        let ctrl = dev.set_power_down(false).unwrap();
+        assert_eq!(u8::from(ctrl), 0b110);
+        assert_eq!(dev.read_register(ReadBackAddr::Control).unwrap(), 0b110);
+        assert_eq!(dev.reg, sim.registers());
+    }
+
+    #[test]
+    fn test_ab_toggle() {
+        let sim = Simulator::new();
+        let mut dev = sim.driver(4.0);
+        dev.set_gain(0xFFFF, ChannelAddress::AllCh).unwrap();
+        dev.set_offset(0x8000, ChannelAddress::AllCh).unwrap();
+        dev.preload(ChannelAddress::AllCh, 0x1000, 0x2000).unwrap();
+        assert_eq!(sim.registers().x1_a, [0x1000; 40]);
+        assert_eq!(sim.registers().x1_b, [0x2000; 40]);
+        assert_eq!(Control::from(dev.reg.control).ab_select, ABSelect::A);
+        dev.pulse_ldac().unwrap();
+        assert_eq!(sim.dac_codes(), [0x1000; 40]);
+
+        dev.set_select(2, 0xFF).unwrap();
+        assert_eq!(sim.dac_codes(), [0x1000; 40]);
+        dev.pulse_ldac().unwrap();
+        assert_eq!(sim.dac_codes()[16..24], [0x2000; 8]);
+        assert_eq!(sim.dac_codes()[8..16], [0x1000; 8]);
+
+        dev.toggle_group(0, 0b1000_0001).unwrap();
+        assert_eq!(sim.dac_codes()[0], 0x2000);
+        assert_eq!(sim.dac_codes()[1], 0x1000);
+        assert_eq!(sim.dac_codes()[7], 0x2000);
+
+        dev.toggle_all(ABSelect::B).unwrap();
+        assert_eq!(sim.dac_codes(), [0x2000; 40]);
+        dev.toggle_all(ABSelect::A).unwrap();
+        assert_eq!(sim.dac_codes(), [0x1000; 40]);
+        assert!(dev.set_select(5, 0).is_err());
+        assert_eq!(dev.reg, sim.registers());
+    }
+
+    #[test]
+    fn test_set_voltage() {
+        let sim = Simulator::new();
+        let mut dev = sim.driver(5.0);
+        dev.set_output_span(-10.0, 10.0).unwrap();
+        dev.set_gain(0xFFFF, ChannelAddress::AllCh).unwrap();
+        dev.set_offset(0x8000, ChannelAddress::AllCh).unwrap();
+        dev.set_gain(0x7FFF, ChannelAddress::SingleCh { ch: 0, group: 1 })
+            .unwrap();
+        dev.set_offset(0x8100, ChannelAddress::SingleCh { ch: 0, group: 1 })
+            .unwrap();
+
+        dev.set_voltage(2.5, ChannelAddress::SingleCh { ch: 0, group: 0 })
+            .unwrap();
+        dev.set_voltage(-2.5, ChannelAddress::SingleCh { ch: 0, group: 1 })
+            .unwrap();
+        dev.pulse_ldac().unwrap();
+        assert_eq!(sim.dac_codes()[0], 0xA000);
+        assert_eq!(sim.dac_codes()[8], 0x6000);
+        assert_eq!(dev.code_to_voltage(dev.reg.x1_a[8], 1, 0).unwrap(), -2.5);
+        assert!(dev.code_to_voltage(0, 5, 0).is_err());
+        assert!(dev.code_to_voltage(0, 0, 8).is_err());
+
+        // With half gain the upper half of the span is out of reach.
+        assert!(dev
+            .set_voltage(9.0, ChannelAddress::SingleCh { ch: 0, group: 1 })
+            .is_err());
+        assert!(dev.set_voltage(10.0, ChannelAddress::AllCh).is_err());
+        assert_eq!(dev.reg, sim.registers());
+    }
+
+    #[test]
+    fn test_busy_wait() {
+        let sim = Simulator::new();
+        let mut dev = sim.driver(4.0);
+        sim.set_busy_reads(3);
+        dev.set_code(0x1000, ChannelAddress::AllCh).unwrap();
+        dev.set_code(0x2000, ChannelAddress::AllCh).unwrap();
+        dev.pulse_ldac().unwrap();
+        // One idle read before each access plus three busy ones after every frame.
+        assert_eq!(sim.busy_reads(), 9);
+
+        sim.set_busy_reads(usize::MAX);
+        dev.cfg.busy_timeout = Some(Duration::from_millis(1));
+        dev.set_code(0x3000, ChannelAddress::AllCh).unwrap();
+        match dev.set_code(0x4000, ChannelAddress::AllCh) {
+            Err(IError::BusyTimeout { waited }) => assert!(waited > Duration::from_millis(1)),
+            _ => panic!("expected a BUSY timeout"),
+        }
+        assert!(dev.pulse_ldac().is_err());
+        assert_eq!(sim.registers().x1_a, [0x3000; 40]);
+    }
+
+    #[test]
+    fn test_init() {
+        let sim = Simulator::new();
+        let mut dev = sim.driver(4.0);
+        dev.set_code(0x1234, ChannelAddress::AllCh).unwrap();
+        dev.set_thermal_shutdown(true).unwrap();
+        dev._clr.reset().unwrap();
+        sim.set_busy_reads(5);
+
+        dev.cfg.hw_reset = true;
+        dev.init().unwrap();
+        assert_eq!(sim.registers(), Register::default());
+        assert_eq!(dev.reg, Register::default());
+        assert!(!sim.is_cleared());
+        // The reset sequence is awaited before the first readback.
+        assert!(sim.busy_reads() >= 6);
+
+        // Without RESET wired the chip keeps its state.
+        dev.set_code(0x1234, ChannelAddress::AllCh).unwrap();
+        dev.cfg.hw_reset = false;
+        dev.init().unwrap();
+        assert_eq!(sim.registers().x1_a, [0x1234; 40]);
+    }
+
+    #[test]
+    fn test_power_on_defaults() {
+        // Datasheet reset values, a default X1 of 0x5555 with OFS at 0x1555 puts every output at 0 V.
+        let reg = Register::default();
+        assert_eq!((reg.x1_a[0], reg.x1_b[39]), (0x5555, 0x5555));
+        assert_eq!((reg.gain[0], reg.offset[0]), (0xFFFF, 0x8000));
+        assert_eq!((reg.ofs0, reg.ofs1, reg.control), (0x1555, 0x1555, 0));
+        let dev = Simulator::new().driver(4.0);
+        assert!(dev.code_to_voltage(0x5555, 0, 0).unwrap().abs() < 1e-3);
+
+        // The 14-bit parts hold the same values right-justified.
+        let reg = Register::for_chip(&Chip::AD5371);
+        assert_eq!(
+            (reg.x1_a[0], reg.gain[0], reg.offset[0]),
+            (0x1555, 0x3FFF, 0x2000)
+        );
+        assert_eq!(reg.ofs2, 0x1555);
+    }
+
+    #[test]
+    fn test_invalid_channel() {
+        let c = Channel::new(37).unwrap();
+        assert_eq!((c.group(), c.ch(), c.index()), (4, 5, 37));
+        assert_eq!(Channel::from_group_ch(4, 5).unwrap(), c);
+        assert!(Channel::new(40).is_err());
+        assert!(ChannelAddress::single(5, 0).is_err());
+        assert!(ChannelAddress::single(0, 8).is_err());
+        assert!(ChannelAddress::chx(8).is_err());
+        assert!(ReadBackAddr::select(5).is_err());
+
+        // Unchecked fields can no longer reach the mode bits.
+        let frame = MainBuilder::default()
+            .write(WriteMode::Data)
+            .address(ChannelAddress::SingleCh { ch: 9, group: 7 })
+            .build();
+        assert_eq!(frame[0] >> 6, WriteMode::Data as u8);
+
+        let sim = Simulator::with_chip(Chip::AD5372);
+        let mut dev = sim.driver(4.0);
+        let frames = sim.frames();
+        for target in [
+            ChannelAddress::SingleCh { ch: 0, group: 4 },
+            ChannelAddress::SingleCh { ch: 8, group: 0 },
+            ChannelAddress::SingleGroup { group: 4 },
+        ]
+        .iter()
+        {
+            match dev.set_code(0x1000, *target) {
+                Err(IError::InvalidChannel { .. }) => {}
+                _ => panic!("expected an invalid channel error"),
+            }
+        }
+        assert!(dev.read_register(ReadBackAddr::x1a(c)).is_err());
+        assert!(dev.voltage_to_input(0.0, 4, 0).is_err());
+        assert!(dev.code_to_voltage(0, 4, 0).is_err());
+        assert_eq!(sim.frames(), frames);
+    }
+
+    #[test]
+    fn test_spi_timing() {
+        let sim = Simulator::new();
+        let mut dev = sim.driver(4.0);
+        sim.set_spi_config(Some(SpiConfig::default()));
+        dev.init().unwrap();
+
+        let frames = sim.frames();
+        sim.set_spi_config(Some(SpiConfig {
+            clock_hz: 40_000_000,
+            ..SpiConfig::default()
+        }));
+        assert!(dev.init().is_err());
+        assert_eq!(sim.frames(), frames);
+    }
+
+    #[test]
+    fn test_readback_frame() {
+        let resp = ReadResp([0x00, 0x12, 0x34]);
+        assert_eq!(
+            (resp.to_u16(), resp.to_u8(), resp.to_u32()),
+            (0x1234, 0x34, 0x1234)
+        );
+        let nop = Command::Function {
+            addr: SpecialFunctionAddress::Nop,
+            data: 0,
+        };
+        assert_eq!(ReadResp::nop().0, nop.encode());
+
+        for chip in Chip::ALL.iter() {
+            let sim = Simulator::with_chip(*chip);
+            let mut dev = sim.driver(4.0);
+            let sent = Arc::new(Mutex::new(Vec::new()));
+            dev.spi = Box::new(Probe(sim.spi(), sent.clone()));
+            let code = chip.max_code() - 1;
+            dev.set_code(code, ChannelAddress::AllCh).unwrap();
+            dev.set_select(1, 0xA5).unwrap();
+            let frames = sim.frames();
+            let reg = sim.registers();
+
+            // 14-bit codes come back left-justified and are shifted down again.
+            let x1a = ReadBackAddr::X1A { group: 3, ch: 7 };
+            assert_eq!(dev.read_register(x1a).unwrap(), code);
+            let select = ReadBackAddr::Select { group: 1 };
+            assert_eq!(dev.read_register(select).unwrap(), 0xA5);
+            // Each readback is the request plus a NOP that leaves the registers alone.
+            assert_eq!(sim.frames(), frames + 4);
+            assert_eq!(sim.registers(), reg);
+            assert_eq!(*sent.lock().unwrap(), vec![nop.encode(); 2]);
+        }
+    }
 }
@@ -3,13 +3,25 @@ use std::{
     marker::{Send, Sync},
 };
 
-use self::driver::AD5370;
+use self::{
+    builder::{Builder, MainBuilder},
+    driver::AD5370,
+    reg::SpecialFunctionAddress,
+};
 
+pub mod batch;
 pub mod builder;
+pub mod chip;
+pub mod command;
+pub mod device;
 pub mod driver;
 pub mod labview;
 pub mod reg;
+pub mod sim;
+pub mod timing;
+pub mod transfer;
 mod utils;
+pub mod verify;
 
 pub type Instance<'a> = AD5370<'a>;
 type AD5370PerChannelRegister = [u16; 40];
@@ -27,6 +39,15 @@ impl ReadResp {
     fn new() -> Self {
         ReadResp([0; 3])
     }
+    /// Data frame of a readback, a NOP so that nothing is written while SDO is shifted out.
+    fn nop() -> Self {
+        ReadResp(
+            MainBuilder::default()
+                .funtion()
+                .address(SpecialFunctionAddress::Nop)
+                .build(),
+        )
+    }
     fn as_mut(&mut self) -> &mut [u8] {
         self.0.as_mut()
     }
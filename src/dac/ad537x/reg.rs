@@ -1,10 +1,16 @@
-use super::{utils::to_ch_seq, AD5370PerChannelRegister};
+use std::convert::TryFrom;
 
-#[derive(Copy, Clone)]
+use super::{chip::Chip, utils::to_ch_seq, AD5370PerChannelRegister};
+use crate::error::IError;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum SpecialFunctionAddress {
+    Nop,
     WriteControl,
     WriteOFS0,
     WriteOFS1,
+    //AD5371 only
+    WriteOFS2,
     ReadBack,
     //Write data in F7:F0 to A/B Select x
     WriteSelect { group: u8 },
@@ -13,9 +19,11 @@ pub enum SpecialFunctionAddress {
 impl From<SpecialFunctionAddress> for u8 {
     fn from(c: SpecialFunctionAddress) -> Self {
         match c {
+            SpecialFunctionAddress::Nop => 0,
             SpecialFunctionAddress::WriteControl => 1,
             SpecialFunctionAddress::WriteOFS0 => 2,
             SpecialFunctionAddress::WriteOFS1 => 3,
+            SpecialFunctionAddress::WriteOFS2 => 4,
             SpecialFunctionAddress::ReadBack => 5,
             SpecialFunctionAddress::WriteSelect { group } => (6 + group),
             SpecialFunctionAddress::WriteSelectAll => 11,
@@ -23,7 +31,119 @@ impl From<SpecialFunctionAddress> for u8 {
     }
 }
 
-#[derive(Copy, Clone)]
+impl TryFrom<u8> for SpecialFunctionAddress {
+    type Error = IError;
+    fn try_from(c: u8) -> Result<Self, Self::Error> {
+        match c {
+            0 => Ok(SpecialFunctionAddress::Nop),
+            1 => Ok(SpecialFunctionAddress::WriteControl),
+            2 => Ok(SpecialFunctionAddress::WriteOFS0),
+            3 => Ok(SpecialFunctionAddress::WriteOFS1),
+            4 => Ok(SpecialFunctionAddress::WriteOFS2),
+            5 => Ok(SpecialFunctionAddress::ReadBack),
+            6..=10 => Ok(SpecialFunctionAddress::WriteSelect { group: c - 6 }),
+            11 => Ok(SpecialFunctionAddress::WriteSelectAll),
+            _ => Err(IError::General {
+                msg: "reserved special function",
+            }),
+        }
+    }
+}
+
+/// Offset DACs, each one shared by a bank of groups.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum OffsetDac {
+    //Sets the offset for Group 0.
+    OFS0,
+    //Sets the offset for the remaining groups, or Group 1 and Group 2 on the AD5371.
+    OFS1,
+    //Sets the offset for Group 3 and Group 4 on the AD5371.
+    OFS2,
+}
+impl From<OffsetDac> for SpecialFunctionAddress {
+    fn from(c: OffsetDac) -> Self {
+        match c {
+            OffsetDac::OFS0 => SpecialFunctionAddress::WriteOFS0,
+            OffsetDac::OFS1 => SpecialFunctionAddress::WriteOFS1,
+            OffsetDac::OFS2 => SpecialFunctionAddress::WriteOFS2,
+        }
+    }
+}
+
+// Largest group count of the family, the address field has room for no more.
+const MAX_GROUPS: u8 = 5;
+
+fn check_group(group: u8, groups: u8) -> Result<(), IError> {
+    if group >= groups {
+        return Err(IError::InvalidChannel {
+            what: "group",
+            value: group.into(),
+        });
+    }
+    Ok(())
+}
+
+fn check_ch(ch: u8) -> Result<(), IError> {
+    if ch > 7 {
+        return Err(IError::InvalidChannel {
+            what: "channel",
+            value: ch.into(),
+        });
+    }
+    Ok(())
+}
+
+/// One output channel by flat index, group * 8 + ch, in 0..40.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Channel(u8);
+impl Channel {
+    pub const COUNT: u8 = MAX_GROUPS * 8;
+
+    pub fn new(idx: u8) -> Result<Self, IError> {
+        if idx >= Self::COUNT {
+            return Err(IError::InvalidChannel {
+                what: "channel index",
+                value: idx.into(),
+            });
+        }
+        Ok(Channel(idx))
+    }
+    pub fn from_group_ch(group: u8, ch: u8) -> Result<Self, IError> {
+        check_group(group, MAX_GROUPS)?;
+        check_ch(ch)?;
+        Ok(Channel(group * 8 + ch))
+    }
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+    pub fn group(self) -> u8 {
+        self.0 / 8
+    }
+    pub fn ch(self) -> u8 {
+        self.0 % 8
+    }
+    /// Rejects channels past the channel count of `chip`.
+    pub fn check(self, chip: &Chip) -> Result<Self, IError> {
+        check_group(self.group(), chip.groups)?;
+        Ok(self)
+    }
+}
+impl TryFrom<u8> for Channel {
+    type Error = IError;
+    fn try_from(idx: u8) -> Result<Self, Self::Error> {
+        Channel::new(idx)
+    }
+}
+impl From<Channel> for ChannelAddress {
+    fn from(c: Channel) -> Self {
+        ChannelAddress::SingleCh {
+            ch: c.ch(),
+            group: c.group(),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum ChannelAddress {
     AllCh,
     SingleCh { ch: u8, group: u8 },
@@ -32,18 +152,85 @@ pub enum ChannelAddress {
     ChxExceptGroup0 { ch: u8 },
 }
 impl From<ChannelAddress> for u8 {
+    /// Out-of-range fields are masked so they can never spill into the mode bits. Use the
+    /// checked constructors or `ChannelAddress::check` to reject them instead.
     fn from(c: ChannelAddress) -> Self {
         match c {
             ChannelAddress::AllCh => 0,
-            ChannelAddress::SingleCh { ch, group } => ((group + 1) << 3) | ch,
-            ChannelAddress::SingleGroup { group } => group + 1,
-            ChannelAddress::Chx { ch } => (6 << 3) | ch,
-            ChannelAddress::ChxExceptGroup0 { ch } => (7 << 3) | ch,
+            ChannelAddress::SingleCh { ch, group } => {
+                ((group.wrapping_add(1) & 0b111) << 3) | (ch & 0b111)
+            }
+            ChannelAddress::SingleGroup { group } => group.wrapping_add(1) & 0b111,
+            ChannelAddress::Chx { ch } => (6 << 3) | (ch & 0b111),
+            ChannelAddress::ChxExceptGroup0 { ch } => (7 << 3) | (ch & 0b111),
+        }
+    }
+}
+impl TryFrom<u8> for ChannelAddress {
+    type Error = IError;
+    fn try_from(c: u8) -> Result<Self, Self::Error> {
+        match (c >> 3, c & 0b111) {
+            (0, 0) => Ok(ChannelAddress::AllCh),
+            (0, group @ 1..=5) => Ok(ChannelAddress::SingleGroup { group: group - 1 }),
+            (group @ 1..=5, ch) => Ok(ChannelAddress::SingleCh {
+                ch,
+                group: group - 1,
+            }),
+            (6, ch) => Ok(ChannelAddress::Chx { ch }),
+            (7, ch) => Ok(ChannelAddress::ChxExceptGroup0 { ch }),
+            _ => Err(IError::General {
+                msg: "reserved channel address",
+            }),
         }
     }
 }
+impl ChannelAddress {
+    pub fn single(group: u8, ch: u8) -> Result<Self, IError> {
+        Ok(Channel::from_group_ch(group, ch)?.into())
+    }
+    pub fn single_group(group: u8) -> Result<Self, IError> {
+        check_group(group, MAX_GROUPS)?;
+        Ok(ChannelAddress::SingleGroup { group })
+    }
+    pub fn chx(ch: u8) -> Result<Self, IError> {
+        check_ch(ch)?;
+        Ok(ChannelAddress::Chx { ch })
+    }
+    pub fn chx_except_group0(ch: u8) -> Result<Self, IError> {
+        check_ch(ch)?;
+        Ok(ChannelAddress::ChxExceptGroup0 { ch })
+    }
+    /// Rejects addresses whose group or channel does not exist on `chip`.
+    pub fn check(self, chip: &Chip) -> Result<Self, IError> {
+        match self {
+            ChannelAddress::AllCh => {}
+            ChannelAddress::SingleCh { ch, group } => {
+                check_group(group, chip.groups)?;
+                check_ch(ch)?;
+            }
+            ChannelAddress::SingleGroup { group } => check_group(group, chip.groups)?,
+            ChannelAddress::Chx { ch } | ChannelAddress::ChxExceptGroup0 { ch } => check_ch(ch)?,
+        }
+        Ok(self)
+    }
+    /// Whether the flat channel index `idx` (group * 8 + ch) is addressed by this target.
+    pub fn contains(self, idx: usize) -> bool {
+        let (group, ch) = ((idx / 8) as u8, (idx % 8) as u8);
+        match self {
+            ChannelAddress::AllCh => true,
+            ChannelAddress::SingleCh { ch: c, group: g } => c == ch && g == group,
+            ChannelAddress::SingleGroup { group: g } => g == group,
+            ChannelAddress::Chx { ch: c } => c == ch,
+            ChannelAddress::ChxExceptGroup0 { ch: c } => c == ch && group != 0,
+        }
+    }
+    /// Expands the address into the flat channel indices it writes to on `chip`.
+    pub fn channels(self, chip: &Chip) -> impl Iterator<Item = usize> {
+        (0..chip.channels()).filter(move |idx| self.contains(*idx))
+    }
+}
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum ReadBackAddr {
     X1A { group: u8, ch: u8 },
     X1B { group: u8, ch: u8 },
@@ -52,8 +239,79 @@ pub enum ReadBackAddr {
     Control,
     OFS0,
     OFS1,
+    OFS2,
     Select { group: u8 },
 }
+impl From<OffsetDac> for ReadBackAddr {
+    fn from(c: OffsetDac) -> Self {
+        match c {
+            OffsetDac::OFS0 => ReadBackAddr::OFS0,
+            OffsetDac::OFS1 => ReadBackAddr::OFS1,
+            OffsetDac::OFS2 => ReadBackAddr::OFS2,
+        }
+    }
+}
+impl ReadBackAddr {
+    pub fn x1a(c: Channel) -> Self {
+        ReadBackAddr::X1A {
+            group: c.group(),
+            ch: c.ch(),
+        }
+    }
+    pub fn x1b(c: Channel) -> Self {
+        ReadBackAddr::X1B {
+            group: c.group(),
+            ch: c.ch(),
+        }
+    }
+    pub fn c(c: Channel) -> Self {
+        ReadBackAddr::C {
+            group: c.group(),
+            ch: c.ch(),
+        }
+    }
+    pub fn m(c: Channel) -> Self {
+        ReadBackAddr::M {
+            group: c.group(),
+            ch: c.ch(),
+        }
+    }
+    pub fn select(group: u8) -> Result<Self, IError> {
+        check_group(group, MAX_GROUPS)?;
+        Ok(ReadBackAddr::Select { group })
+    }
+    /// Rejects registers that do not exist on `chip`.
+    pub fn check(self, chip: &Chip) -> Result<Self, IError> {
+        match self {
+            ReadBackAddr::X1A { group, ch }
+            | ReadBackAddr::X1B { group, ch }
+            | ReadBackAddr::C { group, ch }
+            | ReadBackAddr::M { group, ch } => {
+                check_group(group, chip.groups)?;
+                check_ch(ch)?;
+            }
+            ReadBackAddr::Select { group } => check_group(group, chip.groups)?,
+            ReadBackAddr::OFS2 if chip.ofs_count < 3 => {
+                return Err(IError::General {
+                    msg: "offset dac not present on this chip",
+                })
+            }
+            _ => {}
+        }
+        Ok(self)
+    }
+    /// Whether the register holds an X, M or C code, which is left-justified on the 14-bit parts.
+    pub fn is_code(self) -> bool {
+        !matches!(
+            self,
+            ReadBackAddr::Control
+                | ReadBackAddr::OFS0
+                | ReadBackAddr::OFS1
+                | ReadBackAddr::OFS2
+                | ReadBackAddr::Select { .. }
+        )
+    }
+}
 
 impl From<ReadBackAddr> for u16 {
     fn from(c: ReadBackAddr) -> Self {
@@ -65,11 +323,85 @@ impl From<ReadBackAddr> for u16 {
             ReadBackAddr::Control => (4_u16 << 13) | (1_u16 << 7),
             ReadBackAddr::OFS0 => (4_u16 << 13) | (2_u16 << 7),
             ReadBackAddr::OFS1 => (4_u16 << 13) | (3_u16 << 7),
-            ReadBackAddr::Select { group } => (4_u16 << 13) | ((group + 6) as u16) << 7,
+            ReadBackAddr::OFS2 => (4_u16 << 13) | (4_u16 << 7),
+            ReadBackAddr::Select { group } => (4_u16 << 13) | ((group as u16 + 6) & 0x3F) << 7,
+        }
+    }
+}
+impl TryFrom<u16> for ReadBackAddr {
+    type Error = IError;
+    /// Decodes the data field of a readback request. Bits 6:0 are don't care.
+    fn try_from(c: u16) -> Result<Self, Self::Error> {
+        let addr = ((c >> 7) & 0x3F) as u8;
+        let channel = || Channel::new(addr.wrapping_sub(8));
+        match (c >> 13, addr) {
+            (0, _) => Ok(ReadBackAddr::x1a(channel()?)),
+            (1, _) => Ok(ReadBackAddr::x1b(channel()?)),
+            (2, _) => Ok(ReadBackAddr::c(channel()?)),
+            (3, _) => Ok(ReadBackAddr::m(channel()?)),
+            (4, 1) => Ok(ReadBackAddr::Control),
+            (4, 2) => Ok(ReadBackAddr::OFS0),
+            (4, 3) => Ok(ReadBackAddr::OFS1),
+            (4, 4) => Ok(ReadBackAddr::OFS2),
+            (4, 6..=10) => Ok(ReadBackAddr::Select { group: addr - 6 }),
+            _ => Err(IError::General {
+                msg: "invalid readback address",
+            }),
+        }
+    }
+}
+/// Selects between the A and B copies of the input (X1) and DAC input (X2) registers.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ABSelect {
+    A,
+    B,
+}
+
+/// Decoded view of the control register.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Control {
+    //Whether data writes go to X1A or X1B.
+    pub ab_select: ABSelect,
+    //Power down the outputs when the die temperature exceeds 130°C.
+    pub thermal_shutdown: bool,
+    //Soft power-down of all output amplifiers.
+    pub power_down: bool,
+}
+impl Control {
+    pub const AB_SELECT: u8 = 1 << 2;
+    pub const THERMAL_SHUTDOWN: u8 = 1 << 1;
+    pub const POWER_DOWN: u8 = 1;
+}
+impl From<u8> for Control {
+    fn from(c: u8) -> Self {
+        Self {
+            ab_select: if c & Control::AB_SELECT != 0 {
+                ABSelect::B
+            } else {
+                ABSelect::A
+            },
+            thermal_shutdown: c & Control::THERMAL_SHUTDOWN != 0,
+            power_down: c & Control::POWER_DOWN != 0,
         }
     }
 }
-#[derive(Copy, Clone)]
+impl From<Control> for u8 {
+    fn from(c: Control) -> Self {
+        let mut v = 0;
+        if c.ab_select == ABSelect::B {
+            v |= Control::AB_SELECT;
+        }
+        if c.thermal_shutdown {
+            v |= Control::THERMAL_SHUTDOWN;
+        }
+        if c.power_down {
+            v |= Control::POWER_DOWN;
+        }
+        v
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum WriteMode {
     //Writes to the DAC input data (X) register, depending on the control register A/B bit
     Data = 3,
@@ -78,8 +410,21 @@ pub enum WriteMode {
     //Writes to the DAC gain (M) register
     Gain = 1,
 }
+impl TryFrom<u8> for WriteMode {
+    type Error = IError;
+    fn try_from(c: u8) -> Result<Self, Self::Error> {
+        match c {
+            1 => Ok(WriteMode::Gain),
+            2 => Ok(WriteMode::Offset),
+            3 => Ok(WriteMode::Data),
+            _ => Err(IError::General {
+                msg: "not a register write mode",
+            }),
+        }
+    }
+}
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct Register {
     //Input Data Register A. One for each DAC channel.
     pub x1_a: AD5370PerChannelRegister,
@@ -93,6 +438,8 @@ pub struct Register {
     pub ofs0: u16,
     //Offset DAC 1 data register. Sets the offset for Group 1 to Group 4.
     pub ofs1: u16,
+    //Offset DAC 2 data register, AD5371 only. Takes Group 3 and Group 4 over from OFS1.
+    pub ofs2: u16,
     // Bit 2 = A/B Select
     // Bit 1 = enable(1)/disable(0) temperature shutdown.
     // Bit 0 = soft power-down(1)/power-up(0).
@@ -102,15 +449,76 @@ pub struct Register {
 }
 impl Default for Register {
     fn default() -> Self {
+        Self::for_chip(&Chip::AD5370)
+    }
+}
+impl Register {
+    /// Power-on and reset values of `chip`.
+    /// With OFS at 0x1555 the default X1 of 0x5555 (0x1555 on 14-bit parts) puts every output at 0 V.
+    pub fn for_chip(chip: &Chip) -> Self {
+        let shift = 16 - chip.bits;
         Self {
-            x1_a: [0x1555; 40],
-            x1_b: [0x1555; 40],
-            gain: [0x3FFF; 40],
-            offset: [32768; 40],
+            x1_a: [0x5555 >> shift; 40],
+            x1_b: [0x5555 >> shift; 40],
+            gain: [0xFFFF >> shift; 40],
+            offset: [0x8000 >> shift; 40],
             ofs0: 0x1555,
             ofs1: 0x1555,
+            ofs2: 0x1555,
             control: 0x00,
             select: [0; 5],
         }
     }
+
+    pub fn ofs(&self, dac: OffsetDac) -> u16 {
+        match dac {
+            OffsetDac::OFS0 => self.ofs0,
+            OffsetDac::OFS1 => self.ofs1,
+            OffsetDac::OFS2 => self.ofs2,
+        }
+    }
+
+    pub fn set_ofs(&mut self, dac: OffsetDac, code: u16) {
+        match dac {
+            OffsetDac::OFS0 => self.ofs0 = code,
+            OffsetDac::OFS1 => self.ofs1 = code,
+            OffsetDac::OFS2 => self.ofs2 = code,
+        }
+    }
+
+    /// Applies a gain, offset or data write to every register addressed by `target`.
+    /// Data writes land in X1A or X1B depending on the control register A/B bit.
+    pub fn apply_write(
+        &mut self,
+        chip: &Chip,
+        mode: WriteMode,
+        target: ChannelAddress,
+        value: u16,
+    ) {
+        let ab = self.control & Control::AB_SELECT != 0;
+        for idx in target.channels(chip) {
+            match mode {
+                WriteMode::Gain => self.gain[idx] = value,
+                WriteMode::Offset => self.offset[idx] = value,
+                WriteMode::Data if ab => self.x1_b[idx] = value,
+                WriteMode::Data => self.x1_a[idx] = value,
+            }
+        }
+    }
+
+    /// Stores `value` as read back from `addr`.
+    pub fn apply_readback(&mut self, addr: ReadBackAddr, value: u16) {
+        let idx = |group: u8, ch: u8| group as usize * 8 + ch as usize;
+        match addr {
+            ReadBackAddr::X1A { group, ch } => self.x1_a[idx(group, ch)] = value,
+            ReadBackAddr::X1B { group, ch } => self.x1_b[idx(group, ch)] = value,
+            ReadBackAddr::C { group, ch } => self.offset[idx(group, ch)] = value,
+            ReadBackAddr::M { group, ch } => self.gain[idx(group, ch)] = value,
+            ReadBackAddr::Control => self.control = value as u8,
+            ReadBackAddr::OFS0 => self.ofs0 = value,
+            ReadBackAddr::OFS1 => self.ofs1 = value,
+            ReadBackAddr::OFS2 => self.ofs2 = value,
+            ReadBackAddr::Select { group } => self.select[group as usize] = value as u8,
+        }
+    }
 }
@@ -0,0 +1,477 @@
+//! In-process model of an AD537x sitting behind the `Transactional`/`IOController` interfaces.
+//!
+//! The simulator decodes the 24-bit frames produced by `MainBuilder`, keeps the same register
+//! file the chip does and answers readback requests on the frame following the request. The
+//! LDAC, CLR and RESET pins are modelled so that `AD5370` can be driven completely off-hardware.
+use std::{
+    convert::TryFrom,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use super::{
+    chip::Chip,
+    command::Command,
+    driver::{DriverConfig, AD5370},
+    reg::{ReadBackAddr, Register, SpecialFunctionAddress},
+    transfer,
+};
+use crate::{
+    error::IError,
+    interface::{
+        gpio::IOController,
+        spi::{SpiConfig, Transactional},
+    },
+};
+
+/// Digital pins of the chip that can be driven from the host.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SimPin {
+    Busy,
+    Ldac,
+    Reset,
+    Clr,
+}
+
+struct State {
+    chip: Chip,
+    reg: Register,
+    // DAC registers, i.e. the codes currently presented to the output stages.
+    dac: [u16; 40],
+    // Word shifted out on SDO during the next frame, if a readback was requested.
+    sdo: Option<u16>,
+    ldac_low: bool,
+    clr_low: bool,
+    reset_low: bool,
+    frames: usize,
+    // BUSY is reported low for this many reads after every frame.
+    busy_per_frame: usize,
+    busy_left: usize,
+    busy_reads: usize,
+    // Bus settings reported by `SimSPI`, as if it sat on a physical bus.
+    spi_cfg: Option<SpiConfig>,
+    // Register writes still to be lost on the bus.
+    drop_writes: usize,
+}
+
+impl State {
+    fn new(chip: Chip) -> Self {
+        let mut s = Self {
+            chip,
+            reg: Register::for_chip(&chip),
+            dac: [0; 40],
+            sdo: None,
+            ldac_low: false,
+            clr_low: false,
+            reset_low: false,
+            frames: 0,
+            busy_per_frame: 0,
+            busy_left: 0,
+            busy_reads: 0,
+            spi_cfg: None,
+            drop_writes: 0,
+        };
+        s.load_dac();
+        s
+    }
+
+    fn read_pin(&mut self, pin: SimPin) -> bool {
+        match pin {
+            SimPin::Busy => {
+                self.busy_reads += 1;
+                if self.busy_left == 0 {
+                    return true;
+                }
+                self.busy_left -= 1;
+                false
+            }
+            SimPin::Ldac => !self.ldac_low,
+            SimPin::Clr => !self.clr_low,
+            SimPin::Reset => !self.reset_low,
+        }
+    }
+
+    fn x2(&self, idx: usize) -> u16 {
+        let use_b = self.reg.select[idx / 8] & (1 << (idx % 8)) != 0;
+        let x1 = if use_b {
+            self.reg.x1_b[idx]
+        } else {
+            self.reg.x1_a[idx]
+        };
+        transfer::x1_to_x2(&self.chip, x1, self.reg.gain[idx], self.reg.offset[idx])
+    }
+
+    fn load_dac(&mut self) {
+        for idx in 0..self.chip.channels() {
+            self.dac[idx] = self.x2(idx);
+        }
+    }
+
+    fn process(&mut self, frame: [u8; 3]) -> Result<(), IError> {
+        self.frames += 1;
+        self.busy_left = self.busy_per_frame;
+        let command = Command::decode(frame)?;
+        if self.drop_writes > 0 && command.is_write() {
+            self.drop_writes -= 1;
+            return Ok(());
+        }
+        match command {
+            Command::Write { mode, target, data } => {
+                let data = self.chip.field_to_code(data);
+                self.reg.apply_write(&self.chip, mode, target, data);
+            }
+            Command::Function { addr, data } => self.special_function(addr, data)?,
+            Command::ReadBack(addr) => self.sdo = Some(self.read_back(addr)?),
+        }
+        if self.ldac_low {
+            self.load_dac();
+        }
+        Ok(())
+    }
+
+    fn special_function(&mut self, addr: SpecialFunctionAddress, data: u16) -> Result<(), IError> {
+        let groups = self.chip.groups;
+        match addr {
+            SpecialFunctionAddress::Nop | SpecialFunctionAddress::ReadBack => {}
+            SpecialFunctionAddress::WriteControl => self.reg.control = (data & 0b111) as u8,
+            SpecialFunctionAddress::WriteOFS0 => self.reg.ofs0 = data & 0x3FFF,
+            SpecialFunctionAddress::WriteOFS1 => self.reg.ofs1 = data & 0x3FFF,
+            SpecialFunctionAddress::WriteOFS2 if self.chip.ofs_count == 3 => {
+                self.reg.ofs2 = data & 0x3FFF
+            }
+            SpecialFunctionAddress::WriteSelect { group } if group < groups => {
+                self.reg.select[group as usize] = data as u8
+            }
+            SpecialFunctionAddress::WriteSelectAll => {
+                for group in 0..groups {
+                    self.reg.select[group as usize] = data as u8;
+                }
+            }
+            _ => {
+                return Err(IError::General {
+                    msg: "reserved special function",
+                })
+            }
+        }
+        Ok(())
+    }
+
+    fn read_back(&self, addr: ReadBackAddr) -> Result<u16, IError> {
+        let addr = addr.check(&self.chip)?;
+        let idx = |group: u8, ch: u8| (group * 8 + ch) as usize;
+        let value = match addr {
+            ReadBackAddr::X1A { group, ch } => self.reg.x1_a[idx(group, ch)],
+            ReadBackAddr::X1B { group, ch } => self.reg.x1_b[idx(group, ch)],
+            ReadBackAddr::C { group, ch } => self.reg.offset[idx(group, ch)],
+            ReadBackAddr::M { group, ch } => self.reg.gain[idx(group, ch)],
+            ReadBackAddr::Control => return Ok(self.reg.control as u16),
+            ReadBackAddr::OFS0 => return Ok(self.reg.ofs0),
+            ReadBackAddr::OFS1 => return Ok(self.reg.ofs1),
+            ReadBackAddr::OFS2 => return Ok(self.reg.ofs2),
+            ReadBackAddr::Select { group } => return Ok(self.reg.select[group as usize] as u16),
+        };
+        Ok(self.chip.code_to_field(value))
+    }
+
+    fn write_pin(&mut self, pin: SimPin, high: bool) {
+        match pin {
+            // Driving BUSY low externally only delays LDAC on the real chip; nothing to model.
+            SimPin::Busy => {}
+            SimPin::Ldac => {
+                // Falling edge latches X2 into the DAC registers, holding it low keeps them transparent.
+                if !high {
+                    self.load_dac();
+                }
+                self.ldac_low = !high;
+            }
+            SimPin::Clr => self.clr_low = !high,
+            SimPin::Reset => {
+                // Reset sequence starts on the rising edge.
+                if high && self.reset_low {
+                    *self = State {
+                        reg: Register::for_chip(&self.chip),
+                        sdo: None,
+                        busy_left: self.busy_per_frame,
+                        ..*self
+                    };
+                    self.load_dac();
+                }
+                self.reset_low = !high;
+            }
+        }
+    }
+}
+
+/// Handle to one simulated chip. Clones share the same device state.
+#[derive(Clone)]
+pub struct Simulator {
+    state: Arc<Mutex<State>>,
+}
+
+impl Default for Simulator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Simulator {
+    pub fn new() -> Self {
+        Self::with_chip(Chip::AD5370)
+    }
+
+    pub fn with_chip(chip: Chip) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(State::new(chip))),
+        }
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, State>, IError> {
+        Ok(self.state.lock()?)
+    }
+
+    /// SPI side of the chip (SYNC, SCLK, SDI and SDO).
+    pub fn spi(&self) -> SimSPI {
+        SimSPI { sim: self.clone() }
+    }
+
+    pub fn pin(&self, pin: SimPin) -> SimGPIO {
+        SimGPIO {
+            sim: self.clone(),
+            pin,
+        }
+    }
+
+    /// Builds a driver whose bus and control pins are all wired to this simulator.
+    pub fn driver<'a>(&self, vref: f64) -> AD5370<'a> {
+        let chip = self.state.lock().unwrap().chip;
+        AD5370::new(
+            chip,
+            vref,
+            DriverConfig::default(),
+            Box::new(self.spi()),
+            [
+                Box::new(self.pin(SimPin::Busy)),
+                Box::new(self.pin(SimPin::Ldac)),
+                Box::new(self.pin(SimPin::Reset)),
+                Box::new(self.pin(SimPin::Clr)),
+            ],
+        )
+    }
+
+    /// Current content of the chip's register file.
+    pub fn registers(&self) -> Register {
+        self.state.lock().unwrap().reg
+    }
+
+    /// DAC register codes of all channels, unused entries stay at zero.
+    pub fn dac_codes(&self) -> [u16; 40] {
+        self.state.lock().unwrap().dac
+    }
+
+    /// Whether CLR is asserted, i.e. all outputs are switched to SIGGND.
+    pub fn is_cleared(&self) -> bool {
+        self.state.lock().unwrap().clr_low
+    }
+
+    /// Makes BUSY read low `n` times after every frame, `usize::MAX` keeps it stuck low.
+    pub fn set_busy_reads(&self, n: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.busy_per_frame = n;
+        state.busy_reads = 0;
+    }
+
+    /// Number of times BUSY was sampled since the last `set_busy_reads`.
+    pub fn busy_reads(&self) -> usize {
+        self.state.lock().unwrap().busy_reads
+    }
+
+    /// Number of SPI frames received since the simulator was created.
+    pub fn frames(&self) -> usize {
+        self.state.lock().unwrap().frames
+    }
+
+    /// Makes `SimSPI` report `cfg` as its bus settings.
+    pub fn set_spi_config(&self, cfg: Option<SpiConfig>) {
+        self.state.lock().unwrap().spi_cfg = cfg;
+    }
+
+    /// Loses the next `n` register writes, as a corrupted frame would, while readback requests
+    /// and NOPs still get through.
+    pub fn drop_writes(&self, n: usize) {
+        self.state.lock().unwrap().drop_writes = n;
+    }
+}
+
+pub struct SimSPI {
+    sim: Simulator,
+}
+
+impl Transactional for SimSPI {
+    fn spi_read(&mut self, prefix: &[u8], data: &mut [u8]) -> Result<(), IError> {
+        self.spi_write(prefix)?;
+        let mut state = self.sim.lock()?;
+        let word = state.sdo.take().unwrap_or(0);
+        // Whatever is clocked in while the readback word is shifted out is still a command.
+        if let Ok(frame) = <[u8; 3]>::try_from(&*data) {
+            state.process(frame)?;
+        }
+        for (d, b) in data
+            .iter_mut()
+            .zip([0, (word >> 8) as u8, word as u8].iter())
+        {
+            *d = *b;
+        }
+        Ok(())
+    }
+
+    fn spi_write(&mut self, data: &[u8]) -> Result<(), IError> {
+        if data.len() % 3 != 0 {
+            return Err(IError::General {
+                msg: "AD5370 frames are 24 bits long",
+            });
+        }
+        let mut state = self.sim.lock()?;
+        for frame in data.chunks(3) {
+            state.sdo = None;
+            state.process([frame[0], frame[1], frame[2]])?;
+        }
+        Ok(())
+    }
+
+    fn spi_config(&self) -> Option<SpiConfig> {
+        self.sim.state.lock().unwrap().spi_cfg
+    }
+}
+
+pub struct SimGPIO {
+    sim: Simulator,
+    pin: SimPin,
+}
+
+impl IOController for SimGPIO {
+    fn set(&mut self) -> Result<(), IError> {
+        self.sim.lock()?.write_pin(self.pin, true);
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<(), IError> {
+        self.sim.lock()?.write_pin(self.pin, false);
+        Ok(())
+    }
+
+    fn read(&mut self) -> Result<bool, IError> {
+        Ok(self.sim.lock()?.read_pin(self.pin))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{
+        builder::*,
+        reg::{ChannelAddress, OffsetDac},
+    };
+    use super::*;
+
+    #[test]
+    fn test_ldac_latches_input_registers() {
+        let sim = Simulator::new();
+        let mut dev = sim.driver(4.0);
+        dev.init().unwrap();
+        dev.set_gain(0xFFFF, ChannelAddress::AllCh).unwrap();
+        dev.set_code(0xA000, ChannelAddress::SingleCh { ch: 3, group: 2 })
+            .unwrap();
+        assert_eq!(sim.registers().x1_a[19], 0xA000);
+        assert_ne!(sim.dac_codes()[19], 0xA000);
+
+        dev._ldac.reset().unwrap();
+        assert_eq!(sim.dac_codes()[19], 0xA000);
+        // LDAC held low: DAC registers follow the input registers.
+        dev.set_code(0x1234, ChannelAddress::Chx { ch: 3 }).unwrap();
+        for group in 0..5 {
+            assert_eq!(sim.dac_codes()[group * 8 + 3], 0x1234);
+        }
+        assert_eq!(sim.registers().x1_a[4], Register::default().x1_a[4]);
+    }
+
+    #[test]
+    fn test_readback() {
+        let sim = Simulator::new();
+        let mut dev = sim.driver(4.0);
+        dev.set_offset(0x8100, ChannelAddress::AllCh).unwrap();
+        dev.set_code(0x4242, ChannelAddress::ChxExceptGroup0 { ch: 1 })
+            .unwrap();
+
+        let mut resp = [0_u8; 3];
+        let req = MainBuilder::default()
+            .read(ReadBackAddr::X1A { group: 4, ch: 1 })
+            .build();
+        dev.spi.spi_read(&req, &mut resp).unwrap();
+        assert_eq!([0x00, 0x42, 0x42], resp);
+
+        let req = MainBuilder::default()
+            .read(ReadBackAddr::X1A { group: 0, ch: 1 })
+            .build();
+        dev.spi.spi_read(&req, &mut resp).unwrap();
+        assert_eq!([0x00, 0x55, 0x55], resp);
+
+        let req = MainBuilder::default()
+            .read(ReadBackAddr::C { group: 1, ch: 7 })
+            .build();
+        dev.spi.spi_read(&req, &mut resp).unwrap();
+        assert_eq!([0x00, 0x81, 0x00], resp);
+
+        let req = MainBuilder::default().read(ReadBackAddr::OFS1).build();
+        dev.spi.spi_read(&req, &mut resp).unwrap();
+        assert_eq!([0x00, 0x15, 0x55], resp);
+    }
+
+    #[test]
+    fn test_reset_and_clear() {
+        let sim = Simulator::new();
+        let mut dev = sim.driver(4.0);
+        dev.set_code(0xFFFF, ChannelAddress::AllCh).unwrap();
+        dev.clear().unwrap();
+        assert!(sim.is_cleared());
+        dev.restore_clear().unwrap();
+        assert!(!sim.is_cleared());
+
+        dev.reset().unwrap();
+        assert_eq!(sim.registers().x1_a, Register::default().x1_a);
+    }
+
+    #[test]
+    fn test_14_bit_part() {
+        let sim = Simulator::with_chip(Chip::AD5373);
+        let mut dev = sim.driver(5.0);
+        dev.init().unwrap();
+        assert_eq!(dev.reg, Register::for_chip(&Chip::AD5373));
+        dev.set_code(0x3FFF, ChannelAddress::AllCh).unwrap();
+        dev.set_code(0x1234, ChannelAddress::Chx { ch: 2 }).unwrap();
+        assert!(dev.set_code(0x4000, ChannelAddress::AllCh).is_err());
+        assert!(dev.set_select(4, 0xFF).is_err());
+        dev.pulse_ldac().unwrap();
+        assert_eq!(sim.dac_codes()[2], 0x1234);
+        assert_eq!(sim.dac_codes()[26], 0x1234);
+        // Only four groups, the last eight entries are not channels.
+        assert_eq!(sim.dac_codes()[34], 0);
+        assert_eq!(dev.snapshot().unwrap(), sim.registers());
+        assert_eq!(dev.reg.x1_a[31], 0x3FFF);
+    }
+
+    #[test]
+    fn test_third_offset_dac() {
+        let sim = Simulator::with_chip(Chip::AD5371);
+        let mut dev = sim.driver(5.0);
+        dev.set_offset_dac(OffsetDac::OFS2, 0x2000).unwrap();
+        dev.set_voltage(0.0, ChannelAddress::SingleCh { ch: 0, group: 3 })
+            .unwrap();
+        dev.set_voltage(0.0, ChannelAddress::SingleCh { ch: 0, group: 2 })
+            .unwrap();
+        assert_eq!(sim.registers().x1_a[24], 0x2000);
+        assert_eq!(sim.registers().x1_a[16], 0x1555);
+        assert_eq!(dev.set_output_span(-10.0, 10.0).unwrap(), 0x2000);
+        assert_eq!(dev.snapshot().unwrap().ofs2, 0x2000);
+
+        let mut dev = Simulator::new().driver(5.0);
+        assert!(dev.set_offset_dac(OffsetDac::OFS2, 0x2000).is_err());
+    }
+}
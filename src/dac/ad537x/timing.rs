@@ -0,0 +1,109 @@
+//! Serial interface timing of the AD537x, checked against the SPI settings of a controller.
+//!
+//! The chip latches SDI on the falling edge of SCLK and shifts SDO out on the rising edge, so
+//! only modes 1 and 2 sample where the chip expects. The FTDI backends only implement mode 1,
+//! see `SpiConfig::check_ftdi_mode`. Minimum values are those of the datasheet timing table for
+//! DVCC = 2.5 V to 5.5 V.
+use std::time::Duration;
+
+use embedded_hal::spi::{Mode, MODE_1, MODE_2};
+
+use crate::{error::IError, interface::spi::SpiConfig};
+
+pub struct Timing {
+    /// SCLK cycle time during readback, limited by the SDO valid delay. Longer than t1, the
+    /// cycle time for writes, so it bounds the clock of a bus that does both.
+    pub sclk_cycle_readback: Duration,
+    /// t6, minimum SYNC high time.
+    pub sync_high: Duration,
+    /// t21, minimum SYNC high time between a readback request and the data frame.
+    pub sync_high_readback: Duration,
+}
+
+pub const AD537X: Timing = Timing {
+    sclk_cycle_readback: Duration::from_nanos(50),
+    sync_high: Duration::from_nanos(20),
+    sync_high_readback: Duration::from_nanos(270),
+};
+
+fn mode_ok(mode: Mode) -> bool {
+    mode == MODE_1 || mode == MODE_2
+}
+
+impl Timing {
+    /// Checks `cfg` for both writes and readback at its clock rate.
+    pub fn check(&self, cfg: &SpiConfig) -> Result<(), IError> {
+        if !mode_ok(cfg.mode) {
+            return Err(IError::General {
+                msg: "spi mode must sample on the falling SCLK edge (mode 1 or 2)",
+            });
+        }
+        let max_hz = 1e9 / self.sclk_cycle_readback.as_nanos() as f64;
+        if cfg.clock_hz == 0 || cfg.clock_hz as f64 > max_hz {
+            return Err(IError::OutOfRange {
+                what: "spi clock",
+                value: cfg.clock_hz as f64,
+            });
+        }
+        if cfg.cs_high < self.sync_high {
+            return Err(IError::OutOfRange {
+                what: "cs high time (ns)",
+                value: cfg.cs_high.as_nanos() as f64,
+            });
+        }
+        if cfg.cs_high.max(cfg.inter_frame) < self.sync_high_readback {
+            return Err(IError::OutOfRange {
+                what: "readback inter-frame delay (ns)",
+                value: cfg.inter_frame.as_nanos() as f64,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use embedded_hal::spi::{MODE_0, MODE_3};
+
+    #[test]
+    fn test_timing_table() {
+        let cfg = SpiConfig::default();
+        assert!(AD537X.check(&cfg).is_ok());
+        assert!(AD537X
+            .check(&SpiConfig {
+                mode: MODE_2,
+                clock_hz: 20_000_000,
+                ..cfg
+            })
+            .is_ok());
+
+        for mode in [MODE_0, MODE_3].iter() {
+            let bad = SpiConfig { mode: *mode, ..cfg };
+            assert!(AD537X.check(&bad).is_err());
+        }
+        // Fine for writes, too fast for SDO.
+        let bad = SpiConfig {
+            clock_hz: 30_000_000,
+            ..cfg
+        };
+        assert!(AD537X.check(&bad).is_err());
+        let bad = SpiConfig {
+            cs_high: Duration::from_nanos(5),
+            ..cfg
+        };
+        assert!(AD537X.check(&bad).is_err());
+        let bad = SpiConfig {
+            inter_frame: Duration::from_nanos(100),
+            ..cfg
+        };
+        assert!(AD537X.check(&bad).is_err());
+        // A long CS high time covers t21 on its own.
+        let ok = SpiConfig {
+            cs_high: Duration::from_micros(1),
+            inter_frame: Duration::from_nanos(0),
+            ..cfg
+        };
+        assert!(AD537X.check(&ok).is_ok());
+    }
+}
@@ -0,0 +1,125 @@
+//! AD537x transfer function, X1 -> X2 -> VOUT.
+//!
+//! The input code X1 is trimmed by the per-channel gain (M) and offset (C) registers into the
+//! DAC input code X2, which is then converted to a voltage around the span set by the offset
+//! DAC (OFS). Voltages are relative to the SIGGND pin of the channel.
+use super::chip::Chip;
+use crate::error::IError;
+
+fn full_scale(chip: &Chip) -> i64 {
+    1 << chip.bits
+}
+
+/// X2 = X1 * (M + 1) / 2^N + C - 2^(N - 1), clamped to the DAC range like the chip does.
+pub fn x1_to_x2(chip: &Chip, x1: u16, m: u16, c: u16) -> u16 {
+    let full = full_scale(chip);
+    let x2 = ((x1 as i64 * (m as i64 + 1)) >> chip.bits) + c as i64 - full / 2;
+    x2.clamp(0, full - 1) as u16
+}
+
+/// Smallest X1 that the chip turns into exactly `x2` with the given trims.
+pub fn x2_to_x1(chip: &Chip, x2: u16, m: u16, c: u16) -> Result<u16, IError> {
+    let full = full_scale(chip);
+    let t = x2 as i64 - c as i64 + full / 2;
+    let step = m as i64 + 1;
+    // ceil(t * 2^N / (M + 1)); each X1 step moves X2 by at most one LSB so this is exact.
+    let x1 = (t * full + step - 1).div_euclid(step);
+    if !(0..full).contains(&x1) {
+        return Err(IError::OutOfRange {
+            what: "input code",
+            value: x1 as f64,
+        });
+    }
+    Ok(x1 as u16)
+}
+
+// The offset DACs are 14 bits wide on every part, one OFS LSB is 2^(N - 14) DAC LSBs.
+fn ofs_in_lsb(chip: &Chip, ofs: u16) -> f64 {
+    ofs as f64 * (1 << (chip.bits - 14)) as f64
+}
+
+/// VOUT = 4 * VREF * (X2 - OFS * 2^(N - 14)) / 2^N, i.e. X2 - 4 * OFS on the 16-bit parts.
+pub fn x2_to_voltage(chip: &Chip, x2: u16, ofs: u16, vref: f64) -> f64 {
+    4.0 * vref * (x2 as f64 - ofs_in_lsb(chip, ofs)) / full_scale(chip) as f64
+}
+
+/// Nearest DAC code for `vol`, or an error if it falls outside the span selected by `ofs`.
+pub fn voltage_to_x2(chip: &Chip, vol: f64, ofs: u16, vref: f64) -> Result<u16, IError> {
+    let full = full_scale(chip) as f64;
+    let x2 = (vol * full / (4.0 * vref) + ofs_in_lsb(chip, ofs)).round();
+    if !(0.0..full).contains(&x2) {
+        return Err(IError::OutOfRange {
+            what: "voltage",
+            value: vol,
+        });
+    }
+    Ok(x2 as u16)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const AD5370: Chip = Chip::AD5370;
+    const AD5371: Chip = Chip::AD5371;
+
+    #[test]
+    fn test_gain_offset_trim() {
+        // Default trims pass X1 through unchanged.
+        for x1 in [0, 1, 0x5555, 0x8000, 0xFFFF].iter() {
+            assert_eq!(x1_to_x2(&AD5370, *x1, 0xFFFF, 0x8000), *x1);
+            assert_eq!(x2_to_x1(&AD5370, *x1, 0xFFFF, 0x8000).unwrap(), *x1);
+        }
+        // Half gain, +0x100 offset.
+        assert_eq!(x1_to_x2(&AD5370, 0xFFFF, 0x7FFF, 0x8100), 0x7FFF + 0x100);
+        assert_eq!(x1_to_x2(&AD5370, 0, 0xFFFF, 0x7000), 0);
+        assert_eq!(x1_to_x2(&AD5370, 0xFFFF, 0xFFFF, 0x9000), 0xFFFF);
+
+        for &(m, c) in [(0x7FFF, 0x8100), (0xF000, 0x7F00), (0x3FFF, 0x8000)].iter() {
+            for x2 in (0..=0xFFFF_u32).step_by(97) {
+                if let Ok(x1) = x2_to_x1(&AD5370, x2 as u16, m, c) {
+                    assert_eq!(x1_to_x2(&AD5370, x1, m, c), x2 as u16);
+                }
+            }
+        }
+        assert!(x2_to_x1(&AD5370, 0xFFFF, 0x7FFF, 0x8000).is_err());
+        assert!(x2_to_x1(&AD5370, 0, 0xFFFF, 0x9000).is_err());
+    }
+
+    #[test]
+    fn test_output_voltage() {
+        // Power-on state: OFS = 0x1555 and X2 = 0x5555 is just above 0 V with VREF = 5 V.
+        let v = x2_to_voltage(&AD5370, 0x5555, 0x1555, 5.0);
+        assert!(v > 0.0 && v < 4.0 * 5.0 / 65536.0 + 1e-12);
+
+        // +/-10 V span with OFS = 0x2000.
+        assert_eq!(x2_to_voltage(&AD5370, 0, 0x2000, 5.0), -10.0);
+        assert_eq!(x2_to_voltage(&AD5370, 0x8000, 0x2000, 5.0), 0.0);
+        assert_eq!(voltage_to_x2(&AD5370, -10.0, 0x2000, 5.0).unwrap(), 0);
+        assert_eq!(voltage_to_x2(&AD5370, 0.0, 0x2000, 5.0).unwrap(), 0x8000);
+        assert_eq!(voltage_to_x2(&AD5370, 2.5, 0x2000, 5.0).unwrap(), 0xA000);
+        assert!(voltage_to_x2(&AD5370, 10.0, 0x2000, 5.0).is_err());
+        assert!(voltage_to_x2(&AD5370, -10.001, 0x2000, 5.0).is_err());
+
+        // 0..20 V span with OFS = 0.
+        assert_eq!(voltage_to_x2(&AD5370, 5.0, 0, 5.0).unwrap(), 0x4000);
+        for code in (0..=0xFFFF_u32).step_by(251) {
+            let v = x2_to_voltage(&AD5370, code as u16, 0x1555, 4.0);
+            assert_eq!(voltage_to_x2(&AD5370, v, 0x1555, 4.0).unwrap(), code as u16);
+        }
+    }
+
+    #[test]
+    fn test_14_bit() {
+        // Power-on state of the 14-bit parts: X1 = 0x1555, M = 0x3FFF, C = 0x2000, OFS = 0x1555.
+        assert_eq!(x1_to_x2(&AD5371, 0x1555, 0x3FFF, 0x2000), 0x1555);
+        assert_eq!(x2_to_voltage(&AD5371, 0x1555, 0x1555, 5.0), 0.0);
+        assert_eq!(x1_to_x2(&AD5371, 0x3FFF, 0x1FFF, 0x2000), 0x1FFF);
+
+        assert_eq!(x2_to_voltage(&AD5371, 0, 0x2000, 5.0), -10.0);
+        assert_eq!(voltage_to_x2(&AD5371, 0.0, 0x2000, 5.0).unwrap(), 0x2000);
+        assert_eq!(voltage_to_x2(&AD5371, 5.0, 0x2000, 5.0).unwrap(), 0x3000);
+        assert!(voltage_to_x2(&AD5371, 10.0, 0x2000, 5.0).is_err());
+        assert!(x2_to_x1(&AD5371, 0x3FFF, 0x1FFF, 0x2000).is_err());
+    }
+}
@@ -1,3 +1,4 @@
+/// Readback address of a channel. Out-of-range fields are masked to the 6-bit address field.
 pub fn to_ch_seq(group: u8, ch: u8) -> u16 {
-    (group * 8 + ch + 8) as u16
+    (((group as u16 + 1) & 0b111) << 3) | (ch & 0b111) as u16
 }
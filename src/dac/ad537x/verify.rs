@@ -0,0 +1,223 @@
+//! Readback verification of register writes.
+//!
+//! With `DriverConfig::verify` set, every gain, offset, data and control write is followed by
+//! readback requests for the registers it changed. A register that reads back wrong gets the
+//! write repeated, up to `verify_retries` times, before the write fails with `ReadbackMismatch`.
+//! The shadow registers then take the values read back, so they match the chip again.
+//! `VerifyStats` counts the outcome, so a flaky bus shows up before it loses a write.
+use serde::Serialize;
+
+use super::{
+    driver::AD5370,
+    reg::{Channel, ChannelAddress, Control, ReadBackAddr, WriteMode},
+};
+use crate::error::IError;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct VerifyStats {
+    /// Writes that read back correctly, possibly after retries.
+    pub verified: u64,
+    /// Writes repeated after a mismatch.
+    pub retries: u64,
+    /// Writes that still read back wrong after the last retry.
+    pub failures: u64,
+}
+
+impl<'a> AD5370<'a> {
+    pub fn verify_stats(&self) -> VerifyStats {
+        self.stats
+    }
+
+    pub fn reset_verify_stats(&mut self) {
+        self.stats = VerifyStats::default();
+    }
+
+    /// Registers changed by `writes` with the values they should now hold, X1A or X1B as picked
+    /// by the control register. A register written more than once is checked for its last value.
+    fn write_checks(
+        &self,
+        writes: &[(WriteMode, ChannelAddress, u16)],
+    ) -> Result<Vec<(ReadBackAddr, u16)>, IError> {
+        let ab = self.reg.control & Control::AB_SELECT != 0;
+        let mut checks = Vec::new();
+        for (mode, target, value) in writes {
+            for idx in target.channels(&self.chip) {
+                let c = Channel::new(idx as u8)?;
+                let addr = match mode {
+                    WriteMode::Gain => ReadBackAddr::m(c),
+                    WriteMode::Offset => ReadBackAddr::c(c),
+                    WriteMode::Data if ab => ReadBackAddr::x1b(c),
+                    WriteMode::Data => ReadBackAddr::x1a(c),
+                };
+                match checks.iter_mut().find(|(a, _)| *a == addr) {
+                    Some(check) => check.1 = *value,
+                    None => checks.push((addr, *value)),
+                }
+            }
+        }
+        Ok(checks)
+    }
+
+    /// `verify` for channel register writes.
+    pub(crate) fn verify_write(
+        &mut self,
+        frames: &[[u8; 3]],
+        writes: &[(WriteMode, ChannelAddress, u16)],
+    ) -> Result<(), IError> {
+        if !self.cfg.verify {
+            return Ok(());
+        }
+        let checks = self.write_checks(writes)?;
+        self.verify(frames, &checks)
+    }
+
+    /// Reads back `checks` after `frames` were written and streams `frames` again while any
+    /// register differs. Does nothing unless `cfg.verify` is set.
+    pub(crate) fn verify(
+        &mut self,
+        frames: &[[u8; 3]],
+        checks: &[(ReadBackAddr, u16)],
+    ) -> Result<(), IError> {
+        if !self.cfg.verify {
+            return Ok(());
+        }
+        let mut retries = 0;
+        loop {
+            match self.mismatch(checks)? {
+                None => {
+                    self.stats.verified += 1;
+                    return Ok(());
+                }
+                Some((expected, actual)) if retries >= self.cfg.verify_retries => {
+                    self.stats.failures += 1;
+                    self.resync(checks)?;
+                    return Err(IError::ReadbackMismatch { expected, actual });
+                }
+                Some(_) => {
+                    retries += 1;
+                    self.stats.retries += 1;
+                    self.write_frames(frames)?;
+                }
+            }
+        }
+    }
+
+    // First register of `checks` that reads back wrong, as (expected, actual).
+    fn mismatch(&mut self, checks: &[(ReadBackAddr, u16)]) -> Result<Option<(u16, u16)>, IError> {
+        for (addr, expected) in checks {
+            let actual = self.read_register(*addr)?;
+            if actual != *expected {
+                return Ok(Some((*expected, actual)));
+            }
+        }
+        Ok(None)
+    }
+
+    // Replaces the shadow copies of `checks` with what the chip holds.
+    fn resync(&mut self, checks: &[(ReadBackAddr, u16)]) -> Result<(), IError> {
+        for (addr, _) in checks {
+            let value = self.read_register(*addr)?;
+            self.reg.apply_readback(*addr, value);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{
+        reg::{ABSelect, OffsetDac},
+        sim::Simulator,
+    };
+    use super::*;
+
+    #[test]
+    fn test_retry() {
+        let sim = Simulator::new();
+        let mut dev = sim.driver(4.0);
+        dev.cfg.verify = true;
+        dev.set_gain(0xF000, ChannelAddress::AllCh).unwrap();
+        dev.select_input_register(ABSelect::B).unwrap();
+        assert_eq!(
+            dev.verify_stats(),
+            VerifyStats {
+                verified: 2,
+                ..VerifyStats::default()
+            }
+        );
+
+        // A lost frame is written again and goes through on the retry.
+        sim.drop_writes(1);
+        dev.set_code(0x1234, ChannelAddress::SingleCh { ch: 3, group: 1 })
+            .unwrap();
+        assert_eq!(sim.registers().x1_b[11], 0x1234);
+        sim.drop_writes(2);
+        dev.set_offset_dac(OffsetDac::OFS0, 0x1000).unwrap();
+        assert_eq!(
+            dev.verify_stats(),
+            VerifyStats {
+                verified: 4,
+                retries: 3,
+                failures: 0,
+            }
+        );
+        assert_eq!(dev.reg, sim.registers());
+    }
+
+    #[test]
+    fn test_overlapping_batch() {
+        let sim = Simulator::new();
+        let mut dev = sim.driver(4.0);
+        dev.cfg.verify = true;
+        dev.batch(|b| {
+            b.set_code(1, ChannelAddress::SingleCh { ch: 0, group: 0 })?;
+            b.set_code(2, ChannelAddress::AllCh)?;
+            b.set_code(3, ChannelAddress::SingleGroup { group: 4 })
+        })
+        .unwrap();
+        assert_eq!(sim.dac_codes()[..32], [2; 32]);
+        assert_eq!(sim.dac_codes()[32..], [3; 8]);
+        assert_eq!(
+            dev.verify_stats(),
+            VerifyStats {
+                verified: 1,
+                ..VerifyStats::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_mismatch() {
+        let sim = Simulator::new();
+        let mut dev = sim.driver(4.0);
+        dev.cfg.verify = true;
+        dev.cfg.verify_retries = 1;
+        sim.drop_writes(2);
+        match dev.set_offset(0x7000, ChannelAddress::SingleGroup { group: 2 }) {
+            Err(IError::ReadbackMismatch { expected, actual }) => {
+                assert_eq!((expected, actual), (0x7000, 0x8000))
+            }
+            _ => panic!("expected a readback mismatch"),
+        }
+        assert_eq!(dev.verify_stats().failures, 1);
+        assert_eq!(dev.verify_stats().retries, 1);
+        // The shadow registers keep what the chip holds, not the value that was lost.
+        assert_eq!(dev.reg, sim.registers());
+
+        // Batches are checked before LDAC, so a lost frame never reaches the outputs.
+        sim.drop_writes(2);
+        assert!(dev
+            .batch(|b| b.set_code(0x2000, ChannelAddress::AllCh))
+            .is_err());
+        assert_eq!(sim.dac_codes(), Simulator::new().dac_codes());
+        assert_eq!(dev.verify_stats().failures, 2);
+        assert_eq!(dev.reg, sim.registers());
+
+        dev.reset_verify_stats();
+        dev.cfg.verify = false;
+        sim.drop_writes(1);
+        dev.set_code(0x3000, ChannelAddress::AllCh).unwrap();
+        assert_eq!(dev.verify_stats(), VerifyStats::default());
+        assert_ne!(dev.reg, sim.registers());
+    }
+}
@@ -1,17 +1,78 @@
-use std::{error::Error, fmt::Display};
+use std::{error::Error, fmt::Display, sync::PoisonError, time::Duration};
 
-use actix_web::ResponseError;
+use actix_web::{http::StatusCode, ResponseError};
 use libftd2xx::TimeoutError;
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub enum IError {
     #[allow(dead_code)]
     General {
         msg: &'static str,
     },
+    /// A USB or SPI transfer did not complete in time.
     Timeout {
         source: &'static str,
     },
+    /// An FTDI adapter, spidev node, gpiochip or named board that does not exist.
+    DeviceNotFound {
+        what: &'static str,
+    },
+    /// The bus or a pin driver reported an error.
+    Bus {
+        source: &'static str,
+    },
+    OutOfRange {
+        what: &'static str,
+        value: f64,
+    },
+    /// BUSY stayed low for longer than `DriverConfig::busy_timeout`.
+    BusyTimeout {
+        waited: Duration,
+    },
+    ReadbackMismatch {
+        expected: u16,
+        actual: u16,
+    },
+    InvalidChannel {
+        what: &'static str,
+        value: i64,
+    },
+    /// A thread panicked while holding the lock of a device.
+    PoisonedLock,
+    Config {
+        msg: String,
+    },
+}
+
+impl IError {
+    /// Status code returned through the C interface, 0 meaning success.
+    ///
+    /// | code | error              |
+    /// |------|--------------------|
+    /// | 1    | `General`          |
+    /// | 2    | `Timeout`          |
+    /// | 3    | `DeviceNotFound`   |
+    /// | 4    | `Bus`              |
+    /// | 5    | `OutOfRange`       |
+    /// | 6    | `BusyTimeout`      |
+    /// | 7    | `ReadbackMismatch` |
+    /// | 8    | `InvalidChannel`   |
+    /// | 9    | `PoisonedLock`     |
+    /// | 10   | `Config`           |
+    pub fn code(&self) -> u32 {
+        match self {
+            IError::General { .. } => 1,
+            IError::Timeout { .. } => 2,
+            IError::DeviceNotFound { .. } => 3,
+            IError::Bus { .. } => 4,
+            IError::OutOfRange { .. } => 5,
+            IError::BusyTimeout { .. } => 6,
+            IError::ReadbackMismatch { .. } => 7,
+            IError::InvalidChannel { .. } => 8,
+            IError::PoisonedLock => 9,
+            IError::Config { .. } => 10,
+        }
+    }
 }
 
 impl Error for IError {}
@@ -21,6 +82,20 @@ impl Display for IError {
         match self {
             Self::General { msg } => f.write_str(msg),
             IError::Timeout { source } => write!(f, "timeout! src:{}", source),
+            IError::DeviceNotFound { what } => write!(f, "{} not found", what),
+            IError::Bus { source } => write!(f, "{} error", source),
+            IError::OutOfRange { what, value } => write!(f, "{} out of range: {}", what, value),
+            IError::BusyTimeout { waited } => {
+                write!(f, "BUSY still low after {} us", waited.as_micros())
+            }
+            IError::ReadbackMismatch { expected, actual } => write!(
+                f,
+                "readback mismatch, expected 0x{:04X} got 0x{:04X}",
+                expected, actual
+            ),
+            IError::InvalidChannel { what, value } => write!(f, "invalid {}: {}", what, value),
+            IError::PoisonedLock => f.write_str("device lock poisoned by a panicked thread"),
+            IError::Config { msg } => write!(f, "config: {}", msg),
         }
     }
 }
@@ -33,8 +108,74 @@ impl From<TimeoutError> for IError {
     }
 }
 
+impl<T> From<PoisonError<T>> for IError {
+    fn from(_: PoisonError<T>) -> Self {
+        Self::PoisonedLock
+    }
+}
+
 impl ResponseError for IError {
-    fn status_code(&self) -> actix_web::http::StatusCode {
-        actix_web::http::StatusCode::INTERNAL_SERVER_ERROR
+    fn status_code(&self) -> StatusCode {
+        match self {
+            IError::InvalidChannel { .. } | IError::OutOfRange { .. } => StatusCode::BAD_REQUEST,
+            IError::DeviceNotFound { .. } => StatusCode::NOT_FOUND,
+            IError::Bus { .. } => StatusCode::BAD_GATEWAY,
+            IError::Timeout { .. } | IError::BusyTimeout { .. } => StatusCode::GATEWAY_TIMEOUT,
+            IError::General { .. }
+            | IError::ReadbackMismatch { .. }
+            | IError::PoisonedLock
+            | IError::Config { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    #[test]
+    fn test_codes() {
+        let errors = [
+            IError::General { msg: "x" },
+            IError::Timeout { source: "x" },
+            IError::DeviceNotFound { what: "x" },
+            IError::Bus { source: "x" },
+            IError::OutOfRange {
+                what: "x",
+                value: 0.0,
+            },
+            IError::BusyTimeout {
+                waited: Duration::from_millis(1),
+            },
+            IError::ReadbackMismatch {
+                expected: 0,
+                actual: 1,
+            },
+            IError::InvalidChannel {
+                what: "x",
+                value: 0,
+            },
+            IError::PoisonedLock,
+            IError::Config { msg: "x".into() },
+        ];
+        for (i, e) in errors.iter().enumerate() {
+            assert_eq!(e.code(), i as u32 + 1);
+        }
+        assert_eq!(errors[7].status_code(), StatusCode::BAD_REQUEST);
+        assert_eq!(errors[2].status_code(), StatusCode::NOT_FOUND);
+        assert_eq!(errors[5].status_code(), StatusCode::GATEWAY_TIMEOUT);
+
+        let lock = Arc::new(Mutex::new(0));
+        let poisoner = lock.clone();
+        std::thread::spawn(move || {
+            let _guard = poisoner.lock().unwrap();
+            panic!("poison the lock");
+        })
+        .join()
+        .unwrap_err();
+        let res: Result<(), IError> = lock.lock().map(|_| ()).map_err(IError::from);
+        assert!(matches!(res, Err(IError::PoisonedLock)));
     }
 }
@@ -1,15 +1,18 @@
 use embedded_hal::digital::v2::OutputPin;
 use libftd2xx::Ft4232h;
+use serde::Deserialize;
 
 use crate::error::IError;
 use ftdi_embedded_hal::OutputPin as FtOutPin;
 pub trait IOController: Send + Sync {
     fn set(&mut self) -> Result<(), IError>;
     fn reset(&mut self) -> Result<(), IError>;
-    // fn read(&mut self) -> Result<bool, IError>;
+    /// Samples the level of the pin, `true` meaning high.
+    fn read(&mut self) -> Result<bool, IError>;
 }
 
-#[allow(dead_code)]
+/// Low byte GPIOs of an MPSSE port.
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize)]
 pub enum Pin {
     AD0,
     AD1,
@@ -21,6 +24,13 @@ pub enum Pin {
     AD7,
 }
 
+impl Pin {
+    /// Bit of the pin in the low GPIO byte.
+    pub fn mask(self) -> u8 {
+        1 << self as u8
+    }
+}
+
 // type Q = &'static Lazy<FtHal<Ft4232h, Initialized>>;
 // type PinFactory = Box<dyn Fn() -> FtOutPin<'static, Ft4232h>>;
 #[allow(dead_code)]
@@ -53,4 +63,11 @@ impl<'a> IOController for FtdiGPIOController<'a> {
         self._pin.set_low()?;
         Ok(())
     }
+
+    fn read(&mut self) -> Result<bool, IError> {
+        // The HAL only exposes the MPSSE GPIOs as outputs.
+        Err(IError::General {
+            msg: "ftdi gpio pins can not be read",
+        })
+    }
 }
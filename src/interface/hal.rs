@@ -0,0 +1,257 @@
+//! Adapters from the generic `embedded-hal` traits.
+//!
+//! `HalSPIController` drives any blocking SPI bus plus an output pin as chip select, and
+//! `HalOutput`/`HalInput` wrap single pins, so the `AD5370` driver runs on Linux spidev, other
+//! USB bridges or microcontroller HALs. The bus itself has to be set up by its HAL with the mode
+//! and clock given in the `SpiConfig`; the adapter only enforces the CS timing.
+use std::time::Duration;
+
+use embedded_hal::{
+    blocking::spi::{Transfer, Write},
+    digital::v2::{InputPin, OutputPin},
+};
+
+use super::{
+    gpio::IOController,
+    spi::{CsGuard, SpiConfig, Transactional},
+};
+use crate::error::IError;
+
+fn bus_err<E>(_: E) -> IError {
+    IError::Bus { source: "spi" }
+}
+
+fn pin_err<E>(_: E) -> IError {
+    IError::Bus { source: "gpio pin" }
+}
+
+pub struct HalSPIController<SPI, CS> {
+    spi: SPI,
+    cs: CS,
+    cfg: SpiConfig,
+    cs_guard: CsGuard,
+}
+
+impl<SPI, CS> HalSPIController<SPI, CS>
+where
+    SPI: Write<u8> + Transfer<u8>,
+    CS: OutputPin,
+{
+    /// `spi` must already run with the mode and clock of `cfg`.
+    pub fn new(spi: SPI, mut cs: CS, cfg: SpiConfig) -> Result<Self, IError> {
+        cs.set_high().map_err(pin_err)?;
+        Ok(Self {
+            spi,
+            cs,
+            cfg,
+            cs_guard: CsGuard::new(),
+        })
+    }
+
+    pub fn into_inner(self) -> (SPI, CS) {
+        (self.spi, self.cs)
+    }
+
+    fn cs_low(&mut self, min_high: Duration) -> Result<(), IError> {
+        self.cs_guard.wait(min_high);
+        self.cs.set_low().map_err(pin_err)
+    }
+
+    fn cs_high(&mut self) -> Result<(), IError> {
+        self.cs.set_high().map_err(pin_err)?;
+        self.cs_guard.rise();
+        Ok(())
+    }
+
+    // Runs `f` with CS low and raises CS again even if `f` fails.
+    fn frame<F>(&mut self, min_high: Duration, f: F) -> Result<(), IError>
+    where
+        F: FnOnce(&mut SPI) -> Result<(), IError>,
+    {
+        self.cs_low(min_high)?;
+        let res = f(&mut self.spi);
+        self.cs_high()?;
+        res
+    }
+}
+
+impl<SPI, CS> Transactional for HalSPIController<SPI, CS>
+where
+    SPI: Write<u8> + Transfer<u8> + Send + Sync,
+    CS: OutputPin + Send + Sync,
+{
+    fn spi_read(&mut self, prefix: &[u8], data: &mut [u8]) -> Result<(), IError> {
+        let cfg = self.cfg;
+        self.frame(cfg.cs_high, |spi| spi.write(prefix).map_err(bus_err))?;
+        //AD5370 t21 guard
+        self.frame(cfg.cs_high.max(cfg.inter_frame), |spi| {
+            spi.transfer(data).map(|_| ()).map_err(bus_err)
+        })
+    }
+
+    fn spi_write(&mut self, data: &[u8]) -> Result<(), IError> {
+        let cs_high = self.cfg.cs_high;
+        self.frame(cs_high, |spi| spi.write(data).map_err(bus_err))
+    }
+
+    fn spi_config(&self) -> Option<SpiConfig> {
+        Some(self.cfg)
+    }
+}
+
+/// Output-only pin, e.g. LDAC, RESET or CLR.
+pub struct HalOutput<P>(pub P);
+
+impl<P: OutputPin + Send + Sync> IOController for HalOutput<P> {
+    fn set(&mut self) -> Result<(), IError> {
+        self.0.set_high().map_err(pin_err)
+    }
+
+    fn reset(&mut self) -> Result<(), IError> {
+        self.0.set_low().map_err(pin_err)
+    }
+
+    fn read(&mut self) -> Result<bool, IError> {
+        Err(IError::General {
+            msg: "output pin can not be read",
+        })
+    }
+}
+
+/// Input-only pin, e.g. BUSY.
+pub struct HalInput<P>(pub P);
+
+impl<P: InputPin + Send + Sync> IOController for HalInput<P> {
+    fn set(&mut self) -> Result<(), IError> {
+        Err(IError::General {
+            msg: "input pin can not be driven",
+        })
+    }
+
+    fn reset(&mut self) -> Result<(), IError> {
+        Err(IError::General {
+            msg: "input pin can not be driven",
+        })
+    }
+
+    fn read(&mut self) -> Result<bool, IError> {
+        self.0.is_high().map_err(pin_err)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    #[derive(Clone, Debug, PartialEq)]
+    enum Event {
+        Cs(bool),
+        Write(Vec<u8>),
+        Transfer(Vec<u8>),
+    }
+
+    type Log = Arc<Mutex<Vec<Event>>>;
+
+    struct MockSpi(Log);
+
+    impl Write<u8> for MockSpi {
+        type Error = ();
+        fn write(&mut self, words: &[u8]) -> Result<(), ()> {
+            self.0.lock().unwrap().push(Event::Write(words.to_vec()));
+            Ok(())
+        }
+    }
+
+    impl Transfer<u8> for MockSpi {
+        type Error = ();
+        fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], ()> {
+            self.0.lock().unwrap().push(Event::Transfer(words.to_vec()));
+            if words.len() > 3 {
+                return Err(());
+            }
+            words.iter_mut().for_each(|w| *w = 0xA5);
+            Ok(words)
+        }
+    }
+
+    struct MockPin(Log);
+
+    impl OutputPin for MockPin {
+        type Error = ();
+        fn set_low(&mut self) -> Result<(), ()> {
+            self.0.lock().unwrap().push(Event::Cs(false));
+            Ok(())
+        }
+        fn set_high(&mut self) -> Result<(), ()> {
+            self.0.lock().unwrap().push(Event::Cs(true));
+            Ok(())
+        }
+    }
+
+    impl InputPin for MockPin {
+        type Error = ();
+        fn is_high(&self) -> Result<bool, ()> {
+            Ok(true)
+        }
+        fn is_low(&self) -> Result<bool, ()> {
+            Ok(false)
+        }
+    }
+
+    #[test]
+    fn test_spi_frames() {
+        let log: Log = Arc::new(Mutex::new(Vec::new()));
+        let mut spi = HalSPIController::new(
+            MockSpi(log.clone()),
+            MockPin(log.clone()),
+            SpiConfig::default(),
+        )
+        .unwrap();
+        spi.spi_write(&[0xC8, 0x00, 0x01]).unwrap();
+        let mut data = [0; 3];
+        spi.spi_read(&[0x05, 0x10, 0x00], &mut data).unwrap();
+        assert_eq!(data, [0xA5; 3]);
+        // A failed transfer still releases CS.
+        assert!(spi.spi_read(&[0x05, 0x10, 0x00], &mut [0; 4]).is_err());
+        assert!(spi.spi_config() == Some(SpiConfig::default()));
+
+        use Event::*;
+        let log = log.lock().unwrap();
+        assert_eq!(
+            log[..11],
+            [
+                Cs(true),
+                Cs(false),
+                Write(vec![0xC8, 0x00, 0x01]),
+                Cs(true),
+                Cs(false),
+                Write(vec![0x05, 0x10, 0x00]),
+                Cs(true),
+                Cs(false),
+                Transfer(vec![0; 3]),
+                Cs(true),
+                Cs(false),
+            ]
+        );
+        assert_eq!(log.last(), Some(&Cs(true)));
+    }
+
+    #[test]
+    fn test_pins() {
+        let log: Log = Arc::new(Mutex::new(Vec::new()));
+        let mut ldac = HalOutput(MockPin(log.clone()));
+        ldac.reset().unwrap();
+        ldac.set().unwrap();
+        assert!(ldac.read().is_err());
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec![Event::Cs(false), Event::Cs(true)]
+        );
+
+        let mut busy = HalInput(MockPin(log));
+        assert!(busy.read().unwrap());
+        assert!(busy.set().is_err());
+    }
+}
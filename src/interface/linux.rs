@@ -0,0 +1,307 @@
+//! Linux spidev and GPIO character device backend.
+//!
+//! `SpidevController` sends every call as one `SPI_IOC_MESSAGE`, with the kernel releasing CS
+//! between the frames, so a stream of frames costs a single syscall. `CdevPin` drives or samples
+//! one line of a gpiochip. Both sit on the small `SpiPort`/`LineIo` traits, implemented by the
+//! kernel devices when the `linux` feature is enabled and by `Loopback` everywhere, so a board
+//! setup can be exercised on any machine.
+use std::sync::{Arc, Mutex};
+
+use super::{
+    gpio::IOController,
+    spi::{SpiConfig, Transactional},
+};
+use crate::error::IError;
+
+// Transfers per message, well below the 14-bit size limit of SPI_IOC_MESSAGE.
+const MAX_XFERS: usize = 256;
+
+/// One chip-select frame of a message. Without `rx` the received bytes are dropped.
+pub struct Xfer<'a> {
+    pub tx: &'a [u8],
+    pub rx: Option<&'a mut [u8]>,
+}
+
+/// Kernel side of an SPI bus.
+pub trait SpiPort: Send + Sync {
+    /// Runs `xfers` back to back with CS released between them.
+    fn message(&mut self, xfers: &mut [Xfer]) -> Result<(), IError>;
+}
+
+/// One GPIO line.
+pub trait LineIo: Send + Sync {
+    fn set_value(&self, value: u8) -> Result<(), IError>;
+    fn get_value(&self) -> Result<u8, IError>;
+}
+
+pub struct SpidevController<S> {
+    port: S,
+    cfg: SpiConfig,
+}
+
+impl<S: SpiPort> SpidevController<S> {
+    /// `port` must already run with the mode and clock of `cfg`.
+    pub fn new(port: S, cfg: SpiConfig) -> Self {
+        Self { port, cfg }
+    }
+}
+
+// The kernel keeps CS high for at least 10 us on a cs_change, which covers t21 of the readback.
+impl<S: SpiPort> Transactional for SpidevController<S> {
+    fn spi_read(&mut self, prefix: &[u8], data: &mut [u8]) -> Result<(), IError> {
+        let tx = data.to_vec();
+        self.port.message(&mut [
+            Xfer {
+                tx: prefix,
+                rx: None,
+            },
+            Xfer {
+                tx: &tx,
+                rx: Some(data),
+            },
+        ])
+    }
+
+    fn spi_write(&mut self, data: &[u8]) -> Result<(), IError> {
+        self.port.message(&mut [Xfer { tx: data, rx: None }])
+    }
+
+    fn spi_write_frames(&mut self, frames: &[&[u8]]) -> Result<(), IError> {
+        for chunk in frames.chunks(MAX_XFERS) {
+            let mut xfers: Vec<Xfer> = chunk.iter().map(|tx| Xfer { tx, rx: None }).collect();
+            self.port.message(&mut xfers)?;
+        }
+        Ok(())
+    }
+
+    fn spi_config(&self) -> Option<SpiConfig> {
+        Some(self.cfg)
+    }
+}
+
+pub struct CdevPin<L>(pub L);
+
+impl<L: LineIo> IOController for CdevPin<L> {
+    fn set(&mut self) -> Result<(), IError> {
+        self.0.set_value(1)
+    }
+
+    fn reset(&mut self) -> Result<(), IError> {
+        self.0.set_value(0)
+    }
+
+    fn read(&mut self) -> Result<bool, IError> {
+        Ok(self.0.get_value()? != 0)
+    }
+}
+
+/// Stand-in for a spidev node with MOSI wired to MISO. Every message is recorded, one entry
+/// per frame, and clones share the record.
+#[derive(Clone, Default)]
+pub struct Loopback {
+    messages: Arc<Mutex<Vec<Vec<Vec<u8>>>>>,
+}
+
+impl Loopback {
+    pub fn messages(&self) -> Vec<Vec<Vec<u8>>> {
+        self.messages.lock().map(|m| m.clone()).unwrap_or_default()
+    }
+}
+
+impl SpiPort for Loopback {
+    fn message(&mut self, xfers: &mut [Xfer]) -> Result<(), IError> {
+        let mut frames = Vec::with_capacity(xfers.len());
+        for xfer in xfers.iter_mut() {
+            if let Some(rx) = xfer.rx.as_deref_mut() {
+                if rx.len() != xfer.tx.len() {
+                    return Err(IError::General {
+                        msg: "spi rx and tx lengths differ",
+                    });
+                }
+                rx.copy_from_slice(xfer.tx);
+            }
+            frames.push(xfer.tx.to_vec());
+        }
+        self.messages.lock()?.push(frames);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "linux")]
+mod dev {
+    use ::gpio_cdev::{Chip, LineHandle, LineRequestFlags};
+    use ::spidev::{SpiModeFlags, Spidev, SpidevOptions, SpidevTransfer};
+    use embedded_hal::spi::{MODE_0, MODE_1, MODE_2};
+
+    use super::*;
+
+    fn spi_err<E>(_: E) -> IError {
+        IError::Bus { source: "spidev" }
+    }
+
+    fn line_err<E>(_: E) -> IError {
+        IError::Bus {
+            source: "gpio line",
+        }
+    }
+
+    impl SpiPort for Spidev {
+        fn message(&mut self, xfers: &mut [Xfer]) -> Result<(), IError> {
+            let last = xfers.len().saturating_sub(1);
+            let mut msgs: Vec<SpidevTransfer> = xfers
+                .iter_mut()
+                .enumerate()
+                .map(|(i, xfer)| {
+                    let mut t = match xfer.rx.as_deref_mut() {
+                        Some(rx) => SpidevTransfer::read_write(xfer.tx, rx),
+                        None => SpidevTransfer::write(xfer.tx),
+                    };
+                    t.cs_change = (i < last) as u8;
+                    t
+                })
+                .collect();
+            self.transfer_multiple(&mut msgs).map_err(spi_err)
+        }
+    }
+
+    impl LineIo for LineHandle {
+        fn set_value(&self, value: u8) -> Result<(), IError> {
+            LineHandle::set_value(self, value).map_err(line_err)
+        }
+
+        fn get_value(&self) -> Result<u8, IError> {
+            LineHandle::get_value(self).map_err(line_err)
+        }
+    }
+
+    /// Opens `path`, e.g. "/dev/spidev0.0", and sets it up for `cfg`.
+    pub fn open_spidev(path: &str, cfg: SpiConfig) -> Result<SpidevController<Spidev>, IError> {
+        let mode = if cfg.mode == MODE_0 {
+            SpiModeFlags::SPI_MODE_0
+        } else if cfg.mode == MODE_1 {
+            SpiModeFlags::SPI_MODE_1
+        } else if cfg.mode == MODE_2 {
+            SpiModeFlags::SPI_MODE_2
+        } else {
+            SpiModeFlags::SPI_MODE_3
+        };
+        let mut spi = Spidev::open(path).map_err(|_| IError::DeviceNotFound { what: "spidev" })?;
+        spi.configure(
+            &SpidevOptions::new()
+                .bits_per_word(8)
+                .max_speed_hz(cfg.clock_hz)
+                .mode(mode)
+                .build(),
+        )
+        .map_err(spi_err)?;
+        Ok(SpidevController::new(spi, cfg))
+    }
+
+    /// Requests line `offset` of `chip`, outputs start high.
+    pub fn open_line(
+        chip: &mut Chip,
+        offset: u32,
+        output: bool,
+    ) -> Result<CdevPin<LineHandle>, IError> {
+        let flags = if output {
+            LineRequestFlags::OUTPUT
+        } else {
+            LineRequestFlags::INPUT
+        };
+        let line = chip.get_line(offset).map_err(line_err)?;
+        let handle = line
+            .request(flags, output as u8, "nanodriver")
+            .map_err(line_err)?;
+        Ok(CdevPin(handle))
+    }
+
+    pub fn open_chip(path: &str) -> Result<Chip, IError> {
+        Chip::new(path).map_err(|_| IError::DeviceNotFound { what: "gpiochip" })
+    }
+}
+
+#[cfg(feature = "linux")]
+pub use self::dev::{open_chip, open_line, open_spidev};
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicU8, Ordering};
+
+    use super::*;
+    use crate::dac::ad537x::{
+        chip::Chip,
+        driver::{DriverConfig, AD5370},
+        reg::ChannelAddress,
+    };
+
+    #[derive(Default)]
+    struct MockLine(AtomicU8);
+
+    impl LineIo for Arc<MockLine> {
+        fn set_value(&self, value: u8) -> Result<(), IError> {
+            self.0.store(value, Ordering::SeqCst);
+            Ok(())
+        }
+
+        fn get_value(&self) -> Result<u8, IError> {
+            Ok(self.0.load(Ordering::SeqCst))
+        }
+    }
+
+    #[test]
+    fn test_loopback() {
+        let port = Loopback::default();
+        let mut spi = SpidevController::new(port.clone(), SpiConfig::default());
+        let mut data = [0x00, 0xAB, 0xCD];
+        spi.spi_read(&[0x05, 0x10, 0x00], &mut data).unwrap();
+        assert_eq!(data, [0x00, 0xAB, 0xCD]);
+        assert!(spi.spi_read(&[0x05], &mut []).is_ok());
+
+        let frames: Vec<[u8; 3]> = (0..300_u16)
+            .map(|i| [0xC8, (i >> 8) as u8, i as u8])
+            .collect();
+        let frames: Vec<&[u8]> = frames.iter().map(|f| &f[..]).collect();
+        spi.spi_write_frames(&frames).unwrap();
+
+        let messages = port.messages();
+        assert_eq!(
+            messages[0],
+            vec![vec![0x05, 0x10, 0x00], vec![0x00, 0xAB, 0xCD]]
+        );
+        assert_eq!(messages.len(), 4);
+        assert_eq!(messages[2].len(), MAX_XFERS);
+        assert_eq!(messages[3].len(), 300 - MAX_XFERS);
+        assert_eq!(messages[3][43], vec![0xC8, 0x01, 0x2B]);
+    }
+
+    #[test]
+    fn test_driver_on_loopback() {
+        let port = Loopback::default();
+        let busy = Arc::new(MockLine::default());
+        let ldac = Arc::new(MockLine::default());
+        let pin = |line: &Arc<MockLine>| Box::new(CdevPin(line.clone()));
+        let cfg = DriverConfig {
+            busy_timeout: Some(std::time::Duration::from_millis(1)),
+            ..DriverConfig::default()
+        };
+        let mut dev = AD5370::new(
+            Chip::AD5370,
+            4.0,
+            cfg,
+            Box::new(SpidevController::new(port.clone(), SpiConfig::default())),
+            [
+                pin(&busy),
+                pin(&ldac),
+                pin(&Arc::new(MockLine::default())),
+                pin(&Arc::new(MockLine::default())),
+            ],
+        );
+        // BUSY is active low, a low line times the write out.
+        assert!(dev.set_code(0x1234, ChannelAddress::AllCh).is_err());
+        busy.0.store(1, Ordering::SeqCst);
+        dev.set_code(0x1234, ChannelAddress::AllCh).unwrap();
+        dev.pulse_ldac().unwrap();
+        assert_eq!(ldac.0.load(Ordering::SeqCst), 1);
+        assert_eq!(port.messages(), vec![vec![vec![0xC0, 0x12, 0x34]]]);
+    }
+}
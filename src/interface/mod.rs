@@ -1,2 +1,6 @@
 pub mod gpio;
+pub mod hal;
+pub mod linux;
+pub mod mpsse;
 pub mod spi;
+pub mod trace;
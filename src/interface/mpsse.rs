@@ -0,0 +1,370 @@
+//! SPI and GPIO through raw MPSSE commands.
+//!
+//! `ftdi-embedded-hal` sends one USB transfer per pin change and per SPI write, so every 24-bit
+//! frame costs several round trips. `MpsseBus` appends the chip-select, clock and GPIO commands
+//! to one buffer instead and hands it to the driver in a single transfer, which lets a whole
+//! stream of frames go out at once. It drives the low GPIO byte itself, so inputs such as BUSY
+//! can be sampled as well.
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use libftd2xx::{ClockData, ClockDataOut, Ft4232h, FtdiCommon, MpsseCmdBuilder};
+
+use super::{
+    gpio::{IOController, Pin},
+    spi::{SpiConfig, Transactional},
+};
+use crate::error::IError;
+
+// SCK, MOSI and MISO are fixed to AD0 to AD2 by the MPSSE engine.
+const SCK: u8 = 1 << 0;
+const MOSI: u8 = 1 << 1;
+
+// Shortest time a single Set Data Bits command holds the pins, one cycle of the 60 MHz engine.
+const GPIO_CMD_MIN: Duration = Duration::from_nanos(17);
+
+// Queued bytes after which a long stream is sent part-way.
+const MAX_BATCH: usize = 64 * 1024;
+
+/// Byte stream to and from an MPSSE engine.
+pub trait MpssePort: Send {
+    fn write_all(&mut self, buf: &[u8]) -> Result<(), IError>;
+    fn read_all(&mut self, buf: &mut [u8]) -> Result<(), IError>;
+}
+
+impl MpssePort for Ft4232h {
+    fn write_all(&mut self, buf: &[u8]) -> Result<(), IError> {
+        Ok(FtdiCommon::write_all(self, buf)?)
+    }
+
+    fn read_all(&mut self, buf: &mut [u8]) -> Result<(), IError> {
+        Ok(FtdiCommon::read_all(self, buf)?)
+    }
+}
+
+/// Command queue of one MPSSE port, shared by its SPI and GPIO controllers.
+pub struct MpsseBus<P> {
+    port: P,
+    cfg: SpiConfig,
+    cs: u8,
+    state: u8,
+    direction: u8,
+    cmd: Vec<u8>,
+}
+
+pub type SharedBus<P> = Arc<Mutex<MpsseBus<P>>>;
+
+impl<P: MpssePort> MpsseBus<P> {
+    /// Takes over the low GPIO byte of an initialized MPSSE port. `cs` and `outputs` are driven
+    /// and start high, every other pin but SCK and MOSI is an input. Only SPI mode 1 is
+    /// implemented.
+    pub fn new(port: P, cfg: SpiConfig, cs: Pin, outputs: &[Pin]) -> Result<Self, IError> {
+        cfg.check_ftdi_mode()?;
+        let direction = outputs
+            .iter()
+            .fold(SCK | MOSI | cs.mask(), |dir, pin| dir | pin.mask());
+        let mut bus = Self {
+            port,
+            cfg,
+            cs: cs.mask(),
+            // SCK idles low.
+            state: direction & !(SCK | MOSI),
+            direction,
+            cmd: Vec::new(),
+        };
+        bus.set_pins();
+        bus.flush()?;
+        Ok(bus)
+    }
+
+    pub fn shared(self) -> SharedBus<P> {
+        Arc::new(Mutex::new(self))
+    }
+
+    fn push(&mut self, cmd: MpsseCmdBuilder) {
+        self.cmd.extend_from_slice(cmd.as_slice());
+    }
+
+    fn set_pins(&mut self) {
+        self.push(MpsseCmdBuilder::new().set_gpio_lower(self.state, self.direction));
+    }
+
+    fn cs_low(&mut self) {
+        self.state &= !self.cs;
+        self.set_pins();
+    }
+
+    // Raises CS and repeats the command until it has been held for at least `min`.
+    fn cs_high(&mut self, min: Duration) {
+        self.state |= self.cs;
+        let n = min.as_nanos().div_ceil(GPIO_CMD_MIN.as_nanos());
+        for _ in 0..n.max(1) {
+            self.set_pins();
+        }
+    }
+
+    // SDI is latched on the falling edge, so data changes on the rising one (mode 1).
+    fn queue_frame(&mut self, data: &[u8], min_high: Duration) {
+        self.cs_low();
+        self.push(MpsseCmdBuilder::new().clock_data_out(ClockDataOut::MsbPos, data));
+        self.cs_high(min_high);
+    }
+
+    /// Sends the queued commands.
+    pub fn flush(&mut self) -> Result<(), IError> {
+        if self.cmd.is_empty() {
+            return Ok(());
+        }
+        let res = self.port.write_all(&self.cmd);
+        self.cmd.clear();
+        res
+    }
+
+    /// Writes every frame in its own chip-select cycle, in as few transfers as possible.
+    pub fn write_frames(&mut self, frames: &[&[u8]]) -> Result<(), IError> {
+        for frame in frames {
+            self.queue_frame(frame, self.cfg.cs_high);
+            if self.cmd.len() >= MAX_BATCH {
+                self.flush()?;
+            }
+        }
+        self.flush()
+    }
+
+    /// Writes `prefix`, then clocks `data` out while reading into it, in one transfer.
+    pub fn read(&mut self, prefix: &[u8], data: &mut [u8]) -> Result<(), IError> {
+        // AD5370 t21 guard
+        self.queue_frame(prefix, self.cfg.cs_high.max(self.cfg.inter_frame));
+        self.cs_low();
+        // SDO is shifted out on the rising edge and sampled on the falling one.
+        self.push(MpsseCmdBuilder::new().clock_data(ClockData::MsbNegIn, data));
+        self.cs_high(self.cfg.cs_high);
+        self.push(MpsseCmdBuilder::new().send_immediate());
+        self.flush()?;
+        self.port.read_all(data)
+    }
+
+    /// Drives output `pin`, after everything queued before.
+    pub fn set_pin(&mut self, pin: Pin, high: bool) -> Result<(), IError> {
+        if self.direction & pin.mask() == 0 {
+            return Err(IError::General {
+                msg: "mpsse pin is an input",
+            });
+        }
+        if high {
+            self.state |= pin.mask();
+        } else {
+            self.state &= !pin.mask();
+        }
+        self.set_pins();
+        self.flush()
+    }
+
+    /// Samples `pin`, after everything queued before.
+    pub fn read_pin(&mut self, pin: Pin) -> Result<bool, IError> {
+        self.push(MpsseCmdBuilder::new().gpio_lower().send_immediate());
+        self.flush()?;
+        let mut levels = [0];
+        self.port.read_all(&mut levels)?;
+        Ok(levels[0] & pin.mask() != 0)
+    }
+}
+
+pub struct MpsseSPIController<P> {
+    bus: SharedBus<P>,
+}
+
+impl<P: MpssePort> MpsseSPIController<P> {
+    pub fn new(bus: SharedBus<P>) -> Self {
+        Self { bus }
+    }
+}
+
+impl<P: MpssePort> Transactional for MpsseSPIController<P> {
+    fn spi_read(&mut self, prefix: &[u8], data: &mut [u8]) -> Result<(), IError> {
+        self.bus.lock()?.read(prefix, data)
+    }
+
+    fn spi_write(&mut self, data: &[u8]) -> Result<(), IError> {
+        self.bus.lock()?.write_frames(&[data])
+    }
+
+    fn spi_write_frames(&mut self, frames: &[&[u8]]) -> Result<(), IError> {
+        self.bus.lock()?.write_frames(frames)
+    }
+
+    fn spi_config(&self) -> Option<SpiConfig> {
+        self.bus.lock().ok().map(|bus| bus.cfg)
+    }
+}
+
+pub struct MpsseGPIOController<P> {
+    bus: SharedBus<P>,
+    pin: Pin,
+}
+
+impl<P: MpssePort> MpsseGPIOController<P> {
+    pub fn new_boxed(bus: SharedBus<P>, pin: Pin) -> Box<Self> {
+        Box::new(Self { bus, pin })
+    }
+}
+
+impl<P: MpssePort> IOController for MpsseGPIOController<P> {
+    fn set(&mut self) -> Result<(), IError> {
+        self.bus.lock()?.set_pin(self.pin, true)
+    }
+
+    fn reset(&mut self) -> Result<(), IError> {
+        self.bus.lock()?.set_pin(self.pin, false)
+    }
+
+    fn read(&mut self) -> Result<bool, IError> {
+        self.bus.lock()?.read_pin(self.pin)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    type Writes = Arc<Mutex<Vec<Vec<u8>>>>;
+
+    // Records every transfer and answers reads with `input`.
+    struct Capture {
+        writes: Writes,
+        input: u8,
+    }
+
+    impl MpssePort for Capture {
+        fn write_all(&mut self, buf: &[u8]) -> Result<(), IError> {
+            self.writes.lock().unwrap().push(buf.to_vec());
+            Ok(())
+        }
+
+        fn read_all(&mut self, buf: &mut [u8]) -> Result<(), IError> {
+            buf.iter_mut().for_each(|b| *b = self.input);
+            Ok(())
+        }
+    }
+
+    fn bus(input: u8) -> (SharedBus<Capture>, Writes) {
+        let writes = Arc::new(Mutex::new(Vec::new()));
+        let port = Capture {
+            writes: writes.clone(),
+            input,
+        };
+        let pins = [Pin::AD5, Pin::AD6, Pin::AD7];
+        let bus = MpsseBus::new(port, SpiConfig::default(), Pin::AD3, &pins).unwrap();
+        (bus.shared(), writes)
+    }
+
+    #[test]
+    fn test_frame_stream() {
+        let (bus, writes) = bus(0);
+        // AD4 (BUSY) and AD2 (MISO) are inputs, the rest start high except SCK and MOSI.
+        assert_eq!(writes.lock().unwrap()[0], vec![0x80, 0xE8, 0xEB]);
+
+        let mut spi = MpsseSPIController::new(bus);
+        let frames: Vec<[u8; 3]> = (0..40).map(|i| [0xC8, i, 0x55]).collect();
+        let frames: Vec<&[u8]> = frames.iter().map(|f| &f[..]).collect();
+        spi.spi_write_frames(&frames).unwrap();
+
+        let writes = writes.lock().unwrap();
+        assert_eq!(writes.len(), 2);
+        // CS low, 3 bytes out, CS held high for two commands to cover 20 ns.
+        let frame = [
+            0x80, 0xE0, 0xEB, 0x10, 0x02, 0x00, 0xC8, 0x01, 0x55, 0x80, 0xE8, 0xEB, 0x80, 0xE8,
+            0xEB,
+        ];
+        assert_eq!(writes[1].len(), 40 * frame.len());
+        assert_eq!(&writes[1][frame.len()..2 * frame.len()], &frame[..]);
+    }
+
+    #[test]
+    fn test_read_and_pins() {
+        let (bus, writes) = bus(0x10);
+        let mut spi = MpsseSPIController::new(bus.clone());
+        let mut data = [0; 3];
+        spi.spi_read(&[0x05, 0x10, 0x00], &mut data).unwrap();
+        assert_eq!(data, [0x10; 3]);
+        {
+            let writes = writes.lock().unwrap();
+            let read = &writes[1];
+            // The readback request holds CS high for t21 before the data frame.
+            let t21 = 270 / 17 + 1;
+            assert_eq!(read.len(), 3 + 6 + 3 * t21 + 3 + 6 + 3 * 2 + 1);
+            assert_eq!(read[read.len() - 1], 0x87);
+        }
+
+        let mut busy = MpsseGPIOController::new_boxed(bus.clone(), Pin::AD4);
+        assert!(busy.read().unwrap());
+        assert!(busy.set().is_err());
+        let mut ldac = MpsseGPIOController::new_boxed(bus, Pin::AD5);
+        ldac.reset().unwrap();
+        assert_eq!(
+            writes.lock().unwrap().last().unwrap(),
+            &vec![0x80, 0xC8, 0xEB]
+        );
+    }
+
+    #[test]
+    fn test_driver_readback() {
+        use crate::dac::ad537x::{
+            chip::Chip,
+            command::Command,
+            driver::{DriverConfig, AD5370},
+            reg::{ReadBackAddr, SpecialFunctionAddress},
+        };
+
+        let (bus, writes) = bus(0x5A);
+        let pin =
+            |pin| -> Box<dyn IOController> { MpsseGPIOController::new_boxed(bus.clone(), pin) };
+        let cfg = DriverConfig {
+            busy_timeout: None,
+            ..DriverConfig::default()
+        };
+        let mut dev = AD5370::new(
+            Chip::AD5370,
+            4.0,
+            cfg,
+            Box::new(MpsseSPIController::new(bus.clone())),
+            [pin(Pin::AD4), pin(Pin::AD5), pin(Pin::AD6), pin(Pin::AD7)],
+        );
+        // SDO carries 0x5A5A in the low 16 bits of the data frame.
+        assert_eq!(dev.read_register(ReadBackAddr::OFS1).unwrap(), 0x5A5A);
+
+        let writes = writes.lock().unwrap();
+        assert_eq!(writes.len(), 2);
+        let read = &writes[1];
+        // The data frame follows the prefix frame, the t21 gap and CS low, as one full-duplex
+        // command carrying a NOP.
+        let start = 3 + 6 + 3 * (270 / 17 + 1) + 3;
+        let nop = Command::Function {
+            addr: SpecialFunctionAddress::Nop,
+            data: 0,
+        };
+        assert_eq!(
+            read[start..start + 3],
+            [ClockData::MsbNegIn as u8, 0x02, 0x00]
+        );
+        assert_eq!(read[start + 3..start + 6], nop.encode());
+    }
+
+    #[test]
+    fn test_mode_2_rejected() {
+        let writes: Writes = Arc::new(Mutex::new(Vec::new()));
+        let port = Capture {
+            writes: writes.clone(),
+            input: 0,
+        };
+        let cfg = SpiConfig {
+            mode: embedded_hal::spi::MODE_2,
+            ..SpiConfig::default()
+        };
+        assert!(MpsseBus::new(port, cfg, Pin::AD3, &[]).is_err());
+        // Nothing reached the port.
+        assert!(writes.lock().unwrap().is_empty());
+    }
+}
@@ -2,13 +2,72 @@ use ftdi_embedded_hal::OutputPin as FtOutPin;
 
 use embedded_hal::{
     digital::v2::OutputPin,
-    prelude::{_embedded_hal_blocking_spi_Write, _embedded_hal_spi_FullDuplex},
+    prelude::{_embedded_hal_blocking_spi_Transfer, _embedded_hal_blocking_spi_Write},
+    spi::{Mode, MODE_1},
 };
 use ftdi_embedded_hal as hal;
 use libftd2xx::Ft4232h;
+use std::time::{Duration, Instant};
 
 use crate::error::IError;
 
+/// Bus settings of one SPI controller, enforced by its `Transactional` implementation.
+#[derive(Clone, Copy, PartialEq)]
+pub struct SpiConfig {
+    pub mode: Mode,
+    pub clock_hz: u32,
+    /// Minimum time CS stays high between two frames.
+    pub cs_high: Duration,
+    /// Minimum time between the request and the data frame of a `spi_read`.
+    pub inter_frame: Duration,
+}
+
+impl SpiConfig {
+    /// Rejects every mode but 1. The FTDI backends shift data out on the rising edge with SCLK
+    /// idling low and do not implement the other phase and polarity.
+    pub fn check_ftdi_mode(&self) -> Result<(), IError> {
+        if self.mode != MODE_1 {
+            return Err(IError::General {
+                msg: "the ftdi backends only implement spi mode 1",
+            });
+        }
+        Ok(())
+    }
+}
+
+impl Default for SpiConfig {
+    fn default() -> Self {
+        Self {
+            mode: MODE_1,
+            clock_hz: 10_000_000,
+            cs_high: Duration::from_nanos(20),
+            inter_frame: Duration::from_nanos(270),
+        }
+    }
+}
+
+/// Rising edge of CS that ended the previous frame, to hold CS high for a minimum time before
+/// the next one. Shared by the controllers that drive CS themselves.
+pub(crate) struct CsGuard(Instant);
+
+impl CsGuard {
+    pub(crate) fn new() -> Self {
+        Self(Instant::now())
+    }
+
+    /// Spins until CS has been high for at least `min`.
+    pub(crate) fn wait(&self, min: Duration) {
+        while self.0.elapsed() < min {
+            std::hint::spin_loop();
+        }
+    }
+
+    /// Records that CS just went high.
+    pub(crate) fn rise(&mut self) {
+        self.0 = Instant::now();
+    }
+}
+
 pub trait Transactional: Send + Sync {
     /// Read writes the prefix buffer then reads into the input buffer
     /// Note that the values of the input buffer will also be output, because, SPI...
@@ -16,40 +75,87 @@ pub trait Transactional: Send + Sync {
 
     /// Write writes the prefix buffer then writes the output buffer
     fn spi_write(&mut self, data: &[u8]) -> Result<(), IError>;
+
+    /// Writes every frame in its own chip-select cycle. Backends that can queue bus operations
+    /// override this to send the whole stream at once.
+    fn spi_write_frames(&mut self, frames: &[&[u8]]) -> Result<(), IError> {
+        for frame in frames {
+            self.spi_write(frame)?;
+        }
+        Ok(())
+    }
+
+    /// Bus settings in use, `None` when there is no physical bus to check.
+    fn spi_config(&self) -> Option<SpiConfig> {
+        None
+    }
 }
 
 pub struct FtdiSPIController {
     pub(crate) _spi: hal::Spi<'static, Ft4232h>,
     pub(crate) _cs: FtOutPin<'static, Ft4232h>,
+    pub(crate) cfg: SpiConfig,
+    pub(crate) cs_guard: CsGuard,
 }
 //
 
 unsafe impl Send for FtdiSPIController {}
 unsafe impl Sync for FtdiSPIController {}
-impl Transactional for FtdiSPIController {
-    fn spi_read(&mut self, prefix: &[u8], data: &mut [u8]) -> Result<(), IError> {
-        self._cs.set_low().unwrap();
-
-        self._spi.write(prefix)?;
 
-        self._cs.set_high().unwrap();
+impl FtdiSPIController {
+    pub fn new(
+        mut _spi: hal::Spi<'static, Ft4232h>,
+        mut _cs: FtOutPin<'static, Ft4232h>,
+        cfg: SpiConfig,
+    ) -> Result<Self, IError> {
+        cfg.check_ftdi_mode()?;
+        // The MPSSE clock is set when the port is initialized, only the polarity is per bus.
+        _spi.set_clock_polarity(cfg.mode.polarity);
+        _cs.set_high()?;
+        Ok(Self {
+            _spi,
+            _cs,
+            cfg,
+            cs_guard: CsGuard::new(),
+        })
+    }
 
-        //AD5370 t21 guard
+    fn cs_low(&mut self, min_high: Duration) -> Result<(), IError> {
+        self.cs_guard.wait(min_high);
+        self._cs.set_low()?;
+        Ok(())
+    }
 
-        self._cs.set_low().unwrap();
+    fn cs_high(&mut self) -> Result<(), IError> {
+        self._cs.set_high()?;
+        self.cs_guard.rise();
+        Ok(())
+    }
+}
 
-        for d in data.iter_mut() {
-            *d = self._spi.read().unwrap();
-        }
+impl Transactional for FtdiSPIController {
+    fn spi_read(&mut self, prefix: &[u8], data: &mut [u8]) -> Result<(), IError> {
+        self.cs_low(self.cfg.cs_high)?;
+        self._spi.write(prefix)?;
+        self.cs_high()?;
 
-        self._cs.set_high().unwrap();
-        Ok(())
+        //AD5370 t21 guard
+        self.cs_low(self.cfg.cs_high.max(self.cfg.inter_frame))?;
+        // Full duplex: `data` is clocked out as the next frame while SDO is captured. Whether
+        // this is a single USB transfer depends on the `Transfer` impl of ftdi-embedded-hal; the
+        // mpsse backend queues the frame itself and always reads back in one transfer.
+        let res = self._spi.transfer(data).map(|_| ());
+        self.cs_high()?;
+        Ok(res?)
     }
 
     fn spi_write(&mut self, data: &[u8]) -> Result<(), IError> {
-        self._cs.set_low().unwrap();
+        self.cs_low(self.cfg.cs_high)?;
         self._spi.write(data)?;
-        self._cs.set_high().unwrap();
-        Ok(())
+        self.cs_high()
+    }
+
+    fn spi_config(&self) -> Option<SpiConfig> {
+        Some(self.cfg)
     }
 }
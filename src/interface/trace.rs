@@ -0,0 +1,343 @@
+//! Software trace of the SPI traffic.
+//!
+//! `Recorder` wraps any `Transactional` and appends one JSON line per call to a writer, with the
+//! bytes of every chip-select frame and a timestamp. `replay` re-issues a recorded session to
+//! another `Transactional`, e.g. the real hardware, optionally with the recorded pacing.
+//!
+//! The wrapped call's result is returned unchanged, so a bus write that went through is never
+//! reported as failed. The first trace write error ends the recording and is returned by
+//! `into_inner`.
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::Path,
+    thread,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::spi::{SpiConfig, Transactional};
+use crate::error::IError;
+
+/// One `Transactional` call.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TraceEntry {
+    /// Microseconds since the recorder was created, taken before the call.
+    pub t_us: u64,
+    /// Bytes clocked out, one entry per chip-select frame.
+    /// `spi_write` produces one frame, `spi_read` two: the prefix, then the data buffer, and
+    /// `spi_write_frames` one per frame of the stream.
+    pub frames: Vec<Vec<u8>>,
+    /// Bytes clocked in during the data frame of a `spi_read`.
+    pub read: Option<Vec<u8>>,
+    /// Whether the wrapped call succeeded.
+    pub ok: bool,
+}
+
+fn write_err<E>(_: E) -> IError {
+    IError::General {
+        msg: "can not write trace",
+    }
+}
+
+pub struct Recorder<T, W> {
+    inner: T,
+    out: W,
+    start: Instant,
+    // First failed trace write, after which nothing more is recorded.
+    error: Option<IError>,
+}
+
+impl<T: Transactional> Recorder<T, BufWriter<File>> {
+    /// Records to a new file at `path`, truncating it if it exists.
+    pub fn create<P: AsRef<Path>>(inner: T, path: P) -> Result<Self, IError> {
+        let file = File::create(path).map_err(|_| IError::General {
+            msg: "can not create trace file",
+        })?;
+        Ok(Self::new(inner, BufWriter::new(file)))
+    }
+}
+
+impl<T: Transactional, W: Write + Send + Sync> Recorder<T, W> {
+    pub fn new(inner: T, out: W) -> Self {
+        Self {
+            inner,
+            out,
+            start: Instant::now(),
+            error: None,
+        }
+    }
+
+    /// Error that ended the recording early, if any.
+    pub fn trace_error(&self) -> Option<&IError> {
+        self.error.as_ref()
+    }
+
+    /// Fails if the trace is incomplete.
+    pub fn into_inner(mut self) -> Result<(T, W), IError> {
+        if let Some(e) = self.error {
+            return Err(e);
+        }
+        self.out.flush().map_err(write_err)?;
+        Ok((self.inner, self.out))
+    }
+
+    fn write_entry(&mut self, entry: &TraceEntry) -> Result<(), IError> {
+        serde_json::to_writer(&mut self.out, entry).map_err(write_err)?;
+        self.out.write_all(b"\n").map_err(write_err)?;
+        self.out.flush().map_err(write_err)
+    }
+
+    fn record(&mut self, entry: &TraceEntry) {
+        if self.error.is_none() {
+            self.error = self.write_entry(entry).err();
+        }
+    }
+}
+
+impl<T: Transactional, W: Write + Send + Sync> Transactional for Recorder<T, W> {
+    fn spi_read(&mut self, prefix: &[u8], data: &mut [u8]) -> Result<(), IError> {
+        let t_us = self.start.elapsed().as_micros() as u64;
+        let sent = data.to_vec();
+        let res = self.inner.spi_read(prefix, data);
+        self.record(&TraceEntry {
+            t_us,
+            frames: vec![prefix.to_vec(), sent],
+            read: Some(data.to_vec()),
+            ok: res.is_ok(),
+        });
+        res
+    }
+
+    fn spi_write(&mut self, data: &[u8]) -> Result<(), IError> {
+        let t_us = self.start.elapsed().as_micros() as u64;
+        let res = self.inner.spi_write(data);
+        self.record(&TraceEntry {
+            t_us,
+            frames: vec![data.to_vec()],
+            read: None,
+            ok: res.is_ok(),
+        });
+        res
+    }
+
+    fn spi_write_frames(&mut self, frames: &[&[u8]]) -> Result<(), IError> {
+        let t_us = self.start.elapsed().as_micros() as u64;
+        let res = self.inner.spi_write_frames(frames);
+        self.record(&TraceEntry {
+            t_us,
+            frames: frames.iter().map(|f| f.to_vec()).collect(),
+            read: None,
+            ok: res.is_ok(),
+        });
+        res
+    }
+
+    fn spi_config(&self) -> Option<SpiConfig> {
+        self.inner.spi_config()
+    }
+}
+
+/// Outcome of a replayed session.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ReplayReport {
+    /// Number of calls re-issued.
+    pub calls: usize,
+    /// Reads whose data differed from the recording.
+    pub read_mismatches: usize,
+}
+
+/// Parses a trace written by `Recorder`.
+pub fn load<R: BufRead>(input: R) -> Result<Vec<TraceEntry>, IError> {
+    let mut entries = Vec::new();
+    for line in input.lines() {
+        let line = line.map_err(|_| IError::General {
+            msg: "can not read trace",
+        })?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        entries.push(serde_json::from_str(&line).map_err(|_| IError::General {
+            msg: "malformed trace entry",
+        })?);
+    }
+    Ok(entries)
+}
+
+/// Re-issues `entries` to `dev` in order. With `paced` set the recorded spacing between calls
+/// is kept, otherwise the calls go out back to back. An entry stamped earlier than the first
+/// one, as in a hand-edited trace, is not waited for. Calls that failed while recording are
+/// replayed as well, since the failure may be part of what is being reproduced, but their
+/// errors do not stop the replay.
+pub fn replay(
+    dev: &mut dyn Transactional,
+    entries: &[TraceEntry],
+    paced: bool,
+) -> Result<ReplayReport, IError> {
+    let mut report = ReplayReport::default();
+    let start = Instant::now();
+    let t0 = entries.first().map_or(0, |e| e.t_us);
+    for entry in entries {
+        if paced {
+            let due = Duration::from_micros(entry.t_us.saturating_sub(t0));
+            if let Some(wait) = due.checked_sub(start.elapsed()) {
+                thread::sleep(wait);
+            }
+        }
+        let res = match (entry.frames.as_slice(), &entry.read) {
+            ([data], None) => dev.spi_write(data),
+            (frames, None) if !frames.is_empty() => {
+                let frames: Vec<&[u8]> = frames.iter().map(Vec::as_slice).collect();
+                dev.spi_write_frames(&frames)
+            }
+            ([prefix, sent], Some(read)) => {
+                let mut data = sent.clone();
+                let res = dev.spi_read(prefix, &mut data);
+                if &data != read {
+                    report.read_mismatches += 1;
+                }
+                res
+            }
+            _ => {
+                return Err(IError::General {
+                    msg: "malformed trace entry",
+                })
+            }
+        };
+        // Only calls that succeeded while recording are expected to succeed again.
+        if entry.ok {
+            res?;
+        }
+        report.calls += 1;
+    }
+    Ok(report)
+}
+
+/// Loads the trace at `path` and replays it to `dev`.
+pub fn replay_file<P: AsRef<Path>>(
+    dev: &mut dyn Transactional,
+    path: P,
+    paced: bool,
+) -> Result<ReplayReport, IError> {
+    let file = File::open(path).map_err(|_| IError::General {
+        msg: "can not open trace file",
+    })?;
+    replay(dev, &load(BufReader::new(file))?, paced)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::dac::ad537x::{builder::*, reg::*, sim::Simulator};
+
+    #[test]
+    fn test_record_replay() {
+        let sim = Simulator::new();
+        let mut rec = Recorder::new(sim.spi(), Vec::new());
+        let frame = MainBuilder::default()
+            .write(WriteMode::Data)
+            .address(ChannelAddress::SingleCh { ch: 3, group: 1 })
+            .data(0x1234)
+            .build();
+        rec.spi_write(&frame).unwrap();
+        rec.spi_write(&[0x00, 0x01, 0x02, 0x03]).unwrap_err();
+        let prefix = MainBuilder::default()
+            .read(ReadBackAddr::X1A { group: 1, ch: 3 })
+            .build();
+        let mut data = [0; 3];
+        rec.spi_read(&prefix, &mut data).unwrap();
+        assert_eq!(data, [0x00, 0x12, 0x34]);
+
+        let (_, out) = rec.into_inner().unwrap();
+        let entries = load(out.as_slice()).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].frames, vec![frame.to_vec()]);
+        assert!(!entries[1].ok);
+        assert_eq!(entries[2].frames, vec![prefix.to_vec(), vec![0; 3]]);
+        assert_eq!(entries[2].read, Some(data.to_vec()));
+        assert!(entries.windows(2).all(|w| w[0].t_us <= w[1].t_us));
+
+        let target = Simulator::new();
+        let report = replay(&mut target.spi(), &entries, true).unwrap();
+        assert_eq!(
+            report,
+            ReplayReport {
+                calls: 3,
+                read_mismatches: 0
+            }
+        );
+        assert_eq!(target.registers(), sim.registers());
+
+        // A call that used to succeed and now fails stops the replay.
+        let mut broken = entries.clone();
+        broken[1].ok = true;
+        assert!(replay(&mut Simulator::new().spi(), &broken, false).is_err());
+
+        let mut stale = entries.clone();
+        stale[2].read = Some(vec![0, 0xAB, 0xCD]);
+        let report = replay(&mut Simulator::new().spi(), &stale, false).unwrap();
+        assert_eq!(report.read_mismatches, 1);
+
+        // Out of order timestamps are replayed without waiting instead of overflowing.
+        let mut shuffled = entries.clone();
+        shuffled[0].t_us = shuffled[2].t_us + 1;
+        let report = replay(&mut Simulator::new().spi(), &shuffled, true).unwrap();
+        assert_eq!(report.calls, 3);
+    }
+
+    #[test]
+    fn test_record_stream() {
+        let sim = Simulator::new();
+        let mut rec = Recorder::new(sim.spi(), Vec::new());
+        let frames: Vec<[u8; 3]> = (0..8_u8)
+            .map(|ch| {
+                MainBuilder::default()
+                    .write(WriteMode::Data)
+                    .address(ChannelAddress::SingleCh { ch, group: 0 })
+                    .data(0x1000 + ch as u16)
+                    .build()
+            })
+            .collect();
+        let frames: Vec<&[u8]> = frames.iter().map(|f| &f[..]).collect();
+        rec.spi_write_frames(&frames).unwrap();
+
+        let (_, out) = rec.into_inner().unwrap();
+        let entries = load(out.as_slice()).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].frames.len(), 8);
+
+        let target = Simulator::new();
+        replay(&mut target.spi(), &entries, false).unwrap();
+        assert_eq!(target.registers(), sim.registers());
+    }
+
+    struct Full;
+
+    impl Write for Full {
+        fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+            Err(std::io::ErrorKind::WriteZero.into())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_trace_error() {
+        let sim = Simulator::new();
+        let mut rec = Recorder::new(sim.spi(), Full);
+        let frame = MainBuilder::default()
+            .write(WriteMode::Data)
+            .address(ChannelAddress::AllCh)
+            .data(0x1234)
+            .build();
+        // The frame reached the chip, so the write succeeds although it was not traced.
+        rec.spi_write(&frame).unwrap();
+        rec.spi_write_frames(&[&frame[..]]).unwrap();
+        assert_eq!(sim.registers().x1_a, [0x1234; 40]);
+        assert!(rec.trace_error().is_some());
+        assert!(rec.into_inner().is_err());
+    }
+}
@@ -1,20 +1,44 @@
 extern crate chrono;
 extern crate ftdi_mpsse;
+mod config;
 mod dac;
 mod error;
 mod global;
 mod interface;
 mod log;
+mod registry;
 mod sin;
 mod svc;
 
-use ftdi_embedded_hal::OutputPin as FtPin;
-use global::{FTDI, GLOBAL_AD5370, HANDLE, TERMINATE_SENDER};
-use libftd2xx::Ft4232h;
-use once_cell::sync::Lazy;
+use std::{ffi::CStr, os::raw::c_char, sync::mpsc::TrySendError};
+
+use dac::ad537x::{
+    device::DeviceBuilder,
+    reg::{Channel, ChannelAddress},
+};
+use error::IError;
+use global::{global_ad5370, HANDLE, TERMINATE_SENDER};
+use registry::BoardChannel;
 use sin::Action;
 
-pub static mut PIN: Lazy<FtPin<'static, Ft4232h>> = Lazy::new(|| FTDI.ad3());
+// Status returned to C callers: 0 on success, `IError::code` otherwise.
+fn status(res: Result<(), IError>) -> u32 {
+    match res {
+        Ok(()) => 0,
+        Err(e) => e.code(),
+    }
+}
+
+unsafe fn c_str<'a>(ptr: *const c_char) -> Result<&'a str, IError> {
+    if ptr.is_null() {
+        return Err(IError::General {
+            msg: "null string argument",
+        });
+    }
+    CStr::from_ptr(ptr).to_str().map_err(|_| IError::General {
+        msg: "string argument is not utf-8",
+    })
+}
 
 #[allow(clippy::missing_safety_doc)]
 #[no_mangle]
@@ -25,12 +49,30 @@ pub unsafe extern "C" fn add(code: u16) -> u16 {
 #[allow(clippy::missing_safety_doc)]
 #[no_mangle]
 pub unsafe extern "C" fn set_code_to_all(code: u16) -> u32 {
-    let mut guard = GLOBAL_AD5370.lock().unwrap();
+    status(global_ad5370().and_then(|dev| dev.lock()?.set_code(code, ChannelAddress::AllCh)))
+}
 
-    guard
-        .set_code(code, dac::ad537x::reg::ChannelAddress::AllCh)
-        .unwrap();
-    0
+/// Opens the FT4232H with serial number `serial` and registers it as `name`.
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn open_board(name: *const c_char, serial: *const c_char) -> u32 {
+    status((|| {
+        let (name, serial) = (c_str(name)?, c_str(serial)?);
+        registry::open(name, DeviceBuilder::serial(serial)).map(|_| ())
+    })())
+}
+
+/// Writes `code` to the channel `target`, given as "board:channel", and pulses LDAC so the
+/// output follows. Pending writes to other channels of the board are loaded with it.
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn set_board_code(target: *const c_char, code: u16) -> u32 {
+    status((|| {
+        let target: BoardChannel = c_str(target)?.parse()?;
+        let mut dev = target.device()?.lock()?;
+        dev.set_code(code, target.channel.into())?;
+        dev.pulse_ldac()
+    })())
 }
 
 #[allow(clippy::missing_safety_doc)]
@@ -42,7 +84,8 @@ pub unsafe extern "C" fn start() -> u32 {
 #[no_mangle]
 pub unsafe extern "C" fn stop() -> u32 {
     if let Some(h) = TERMINATE_SENDER.as_mut() {
-        h.send(Action::Stop).unwrap();
+        // The generator may already have stopped on a device error.
+        h.send(Action::Stop).unwrap_or_default();
         TERMINATE_SENDER.take();
     }
     let handle = HANDLE.take();
@@ -53,17 +96,40 @@ pub unsafe extern "C" fn stop() -> u32 {
     0
 }
 
+/// Queues a new frequency and code for `channel` of the sine generator, starting it on first use.
+///
+/// Returns 0 once queued, like every other function here. A channel past 39 returns the
+/// `InvalidChannel` code (8). Without a generator the error that kept the default board from
+/// opening is returned, or `General` (1) after `stop`. A full queue returns `Timeout` (2), the
+/// call can be repeated; a generator that stopped on a device error returns `General` (1).
 #[allow(clippy::missing_safety_doc)]
 #[no_mangle]
 pub unsafe extern "C" fn set_data(channel: u8, freq: f64, code: u16) -> u32 {
-    HANDLE.as_mut();
-    if let Some(h) = TERMINATE_SENDER.as_mut() {
-        h.try_send(Action::SetData {
-            channel,
-            freq,
-            code,
-        })
-        .unwrap_or_default();
+    // Reject the request rather than index past the 40 channels of the generator.
+    if let Err(e) = Channel::new(channel) {
+        return e.code();
     }
-    1
+    let sender = match (HANDLE.as_ref(), TERMINATE_SENDER.as_mut()) {
+        (Some(_), Some(sender)) => sender,
+        // No generator: the default board did not open, or `stop` ended it.
+        _ => {
+            let stopped = IError::General {
+                msg: "sine generator is stopped",
+            };
+            return status(global_ad5370().and(Err(stopped)));
+        }
+    };
+    let res = sender.try_send(Action::SetData {
+        channel,
+        freq,
+        code,
+    });
+    status(res.map_err(|e| match e {
+        TrySendError::Full(_) => IError::Timeout {
+            source: "sine generator queue",
+        },
+        TrySendError::Disconnected(_) => IError::General {
+            msg: "sine generator stopped on an error",
+        },
+    }))
 }
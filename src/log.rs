@@ -7,12 +7,18 @@ use std::{
 use chrono::{DateTime, Utc};
 use once_cell::sync::Lazy;
 
-static mut LOG_FILE: Lazy<File> = Lazy::new(|| {
+use crate::{config::LogConfig, global::config};
+
+// `None` when the log file can not be opened, logging is then skipped.
+static mut LOG_FILE: Lazy<Option<File>> = Lazy::new(|| {
     fs::OpenOptions::new()
-        .write(true)
+        .create(true)
         .append(true)
-        .open("C:\\Users\\cn\\file.txt")
-        .unwrap()
+        .open(match config() {
+            Ok(cfg) => cfg.log.path.clone(),
+            Err(_) => LogConfig::default().path,
+        })
+        .ok()
 });
 
 pub unsafe fn log(data: &[u8]) {
@@ -20,8 +26,11 @@ pub unsafe fn log(data: &[u8]) {
     let datetime = DateTime::<Utc>::from(now);
     let timestamp_str = datetime.format("%Y-%m-%d %H:%M:%S.%f").to_string();
 
-    LOG_FILE.write_all(timestamp_str.as_bytes()).unwrap();
-
-    LOG_FILE.write_all(data).unwrap();
-    LOG_FILE.write_all(b"\n").unwrap();
+    if let Some(file) = LOG_FILE.as_mut() {
+        // A failed log write is not worth failing the caller for.
+        let _ = file
+            .write_all(timestamp_str.as_bytes())
+            .and_then(|_| file.write_all(data))
+            .and_then(|_| file.write_all(b"\n"));
+    }
 }
@@ -1,27 +1,34 @@
 // #[macro_use]
 extern crate ftdi_mpsse;
+mod config;
 mod dac;
 mod error;
 mod global;
 mod interface;
+mod log;
+mod registry;
+mod sin;
 mod svc;
 mod test;
-mod sin;
-mod log;
 
 use actix_web::{middleware, App, HttpServer};
 
 /// Transaction enum defines possible SPI transactions
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
+    let cfg = global::config()
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string()))?;
     HttpServer::new(move || {
         App::new()
             .wrap(middleware::Logger::default())
             // .data(state.clone())
             .service(svc::ping)
             .service(svc::voltage)
+            .service(svc::code)
+            .service(svc::boards)
+            .service(svc::stats)
     })
-    .bind("127.0.0.1:8080")?
+    .bind(&cfg.server.bind)?
     .run()
     .await
     // todo!()
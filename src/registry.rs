@@ -0,0 +1,173 @@
+//! Named DAC boards shared by the FFI and HTTP layers. The sine generator started by `start`
+//! and `set_data` only drives the default board.
+//!
+//! Boards are registered once and live for the rest of the process. The first configured board
+//! is `GLOBAL_AD5370`, reachable by its name or as `default`; the others are opened on first
+//! use. Single channels are addressed as `board:channel`, with the
+//! flat channel index group * 8 + ch, e.g. `rack2:17`.
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::{Mutex, RwLock},
+};
+
+use once_cell::sync::Lazy;
+
+use crate::{
+    dac::ad537x::{device::DeviceBuilder, driver::AD5370, reg::Channel},
+    error::IError,
+    global::{config, global_ad5370},
+};
+
+pub type Board = &'static Mutex<AD5370<'static>>;
+
+/// Name under which `GLOBAL_AD5370` is reachable.
+pub const DEFAULT_BOARD: &str = "default";
+
+static BOARDS: Lazy<RwLock<HashMap<String, Board>>> = Lazy::new(|| RwLock::new(HashMap::new()));
+
+// Held while a board is opened, so that two callers never open the same port.
+static OPENING: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+fn check_name(boards: &HashMap<String, Board>, name: &str) -> Result<(), IError> {
+    if name.is_empty() || name.contains(':') || name == DEFAULT_BOARD {
+        return Err(IError::General {
+            msg: "invalid board name",
+        });
+    }
+    if boards.contains_key(name) {
+        return Err(IError::General {
+            msg: "board name already registered",
+        });
+    }
+    Ok(())
+}
+
+/// Makes `dev` reachable as `name`. Names must be unique and can not contain ':'.
+pub fn register(name: &str, dev: AD5370<'static>) -> Result<Board, IError> {
+    let mut boards = BOARDS.write()?;
+    check_name(&boards, name)?;
+    let board: Board = Box::leak(Box::new(Mutex::new(dev)));
+    boards.insert(name.to_string(), board);
+    Ok(board)
+}
+
+/// Opens a board with `builder` and registers it as `name`. The name is checked before the
+/// device is opened.
+pub fn open(name: &str, builder: DeviceBuilder) -> Result<Board, IError> {
+    let _opening = OPENING.lock()?;
+    check_name(&*BOARDS.read()?, name)?;
+    register(name, builder.build()?)
+}
+
+/// Looks `name` up, opening it on first use if it is one of the configured boards.
+pub fn get(name: &str) -> Result<Board, IError> {
+    if let Some(board) = BOARDS.read()?.get(name) {
+        return Ok(*board);
+    }
+    let cfg = config()?;
+    if name == DEFAULT_BOARD || name == cfg.boards[0].name {
+        return global_ad5370();
+    }
+    let board = cfg
+        .board(name)
+        .ok_or(IError::DeviceNotFound { what: "board" })?;
+    let _opening = OPENING.lock()?;
+    // Another caller may have opened it while this one waited.
+    if let Some(board) = BOARDS.read()?.get(name) {
+        return Ok(*board);
+    }
+    register(name, board.open()?)
+}
+
+/// Names of the registered boards, without `default`.
+pub fn names() -> Result<Vec<String>, IError> {
+    let mut names: Vec<String> = BOARDS.read()?.keys().cloned().collect();
+    names.sort();
+    Ok(names)
+}
+
+/// A single channel of a named board, parsed from `board:channel`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BoardChannel {
+    pub board: String,
+    pub channel: Channel,
+}
+
+impl BoardChannel {
+    pub fn device(&self) -> Result<Board, IError> {
+        get(&self.board)
+    }
+}
+
+impl FromStr for BoardChannel {
+    type Err = IError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (board, channel) = match s.rfind(':') {
+            Some(idx) => (&s[..idx], &s[idx + 1..]),
+            None => {
+                return Err(IError::General {
+                    msg: "expected board:channel",
+                })
+            }
+        };
+        if board.is_empty() {
+            return Err(IError::General {
+                msg: "expected board:channel",
+            });
+        }
+        let idx = channel.parse::<u8>().map_err(|_| IError::General {
+            msg: "channel is not a number",
+        })?;
+        Ok(BoardChannel {
+            board: board.to_string(),
+            channel: Channel::new(idx)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::dac::ad537x::sim::Simulator;
+
+    #[test]
+    fn test_board_channel() {
+        let bc: BoardChannel = "rack2:17".parse().unwrap();
+        assert_eq!(bc.board, "rack2");
+        assert_eq!((bc.channel.group(), bc.channel.ch()), (2, 1));
+        assert!("rack2".parse::<BoardChannel>().is_err());
+        assert!(":3".parse::<BoardChannel>().is_err());
+        assert!("rack2:x".parse::<BoardChannel>().is_err());
+        match "rack2:40".parse::<BoardChannel>() {
+            Err(IError::InvalidChannel { .. }) => {}
+            _ => panic!("expected an invalid channel error"),
+        }
+    }
+
+    #[test]
+    fn test_registry() {
+        let (a, b) = (Simulator::new(), Simulator::new());
+        register("test-registry-a", a.driver(4.0)).unwrap();
+        register("test-registry-b", b.driver(4.0)).unwrap();
+        assert!(register("test-registry-a", Simulator::new().driver(4.0)).is_err());
+        assert!(register("bad:name", Simulator::new().driver(4.0)).is_err());
+        // Taken and invalid names fail before any device is opened.
+        let res = open("test-registry-a", DeviceBuilder::serial("missing"));
+        assert!(matches!(res, Err(IError::General { msg }) if msg.contains("already")));
+        let res = open(DEFAULT_BOARD, DeviceBuilder::serial("missing"));
+        assert!(matches!(res, Err(IError::General { msg }) if msg.contains("invalid")));
+        assert!(names().unwrap().contains(&"test-registry-b".to_string()));
+        assert!(matches!(
+            get("test-registry-c"),
+            Err(IError::DeviceNotFound { .. })
+        ));
+
+        let target: BoardChannel = "test-registry-b:17".parse().unwrap();
+        let mut dev = target.device().unwrap().lock().unwrap();
+        dev.set_code(0x1234, target.channel.into()).unwrap();
+        dev.pulse_ldac().unwrap();
+        assert_eq!(b.registers().x1_a[17], 0x1234);
+        assert_eq!(a.registers().x1_a[17], 0x5555);
+    }
+}
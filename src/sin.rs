@@ -3,12 +3,13 @@ use std::sync::MutexGuard;
 
 use crate::dac::ad537x::driver::AD5370;
 use crate::dac::ad537x::reg::ChannelAddress;
-use crate::global::GLOBAL_AD5370;
-#[derive(Debug)]
+use crate::error::IError;
+use crate::registry::Board;
 pub struct SinExeciter {
     freq: [f64; 40],
     amplitude: [u16; 40],
     done_ch: Receiver<Action>,
+    dev: Board,
     iter: u128,
     sample_rate: u64,
 }
@@ -19,11 +20,12 @@ pub enum Action {
 }
 
 impl SinExeciter {
-    pub fn new(done_ch: Receiver<Action>) -> Self {
+    pub fn new(done_ch: Receiver<Action>, dev: Board) -> Self {
         Self {
             freq: [10.0; 40],
             amplitude: [0xF000; 40],
             done_ch,
+            dev,
             iter: 0,
             sample_rate: 175,
         }
@@ -47,38 +49,41 @@ impl SinExeciter {
         lock.set_code(amp, ChannelAddress::AllCh).unwrap_or(());
         lock._ldac.reset().unwrap_or(());
     }
-    fn inner_run(&mut self, lock: &mut MutexGuard<AD5370>) {
+    fn inner_run(&mut self, lock: &mut MutexGuard<AD5370>) -> Result<(), IError> {
         self.iter += 1;
-        for (i, freq) in self.freq.iter().enumerate() {
-            let sample_per_period = self.sample_rate as f64 / freq;
-            let sample_index = self.iter as u64 % sample_per_period.round() as u64;
-            let phase = sample_index as f64 / sample_per_period as f64;
-            let amp = f64::sin((2_f64 * std::f64::consts::PI) * phase);
-            let amp = (amp + 1.0) / 2.0;
-            let amp = amp * self.amplitude[i] as f64;
-            let (amp, _) = u16::overflowing_add(amp.round() as u16, 0);
+        let (iter, sample_rate) = (self.iter, self.sample_rate);
+        let (freqs, amplitude) = (&self.freq, &self.amplitude);
+        let res = lock.batch(|b| {
+            for (i, freq) in freqs.iter().enumerate() {
+                let sample_per_period = sample_rate as f64 / freq;
+                let sample_index = iter as u64 % sample_per_period.round() as u64;
+                let phase = sample_index as f64 / sample_per_period as f64;
+                let amp = f64::sin((2_f64 * std::f64::consts::PI) * phase);
+                let amp = (amp + 1.0) / 2.0;
+                let amp = amp * amplitude[i] as f64;
+                let (amp, _) = u16::overflowing_add(amp.round() as u16, 0);
 
-            if lock
-                .set_code(
+                b.set_code(
                     amp,
                     ChannelAddress::SingleCh {
                         ch: i as u8 % 8,
                         group: i as u8 / 8,
                     },
-                )
-                .is_err()
-            {
-                std::process::exit(0);
+                )?;
             }
-        }
+            Ok(())
+        });
+        res.map(|_| ())
     }
-    pub fn run(&mut self) {
-        let mut lock = GLOBAL_AD5370.lock().unwrap();
-        lock.set_gain(0xF000).unwrap();
-        lock.set_offset(0x8000).unwrap();
+    /// Generates samples until told to stop, or until the device reports an error.
+    pub fn run(&mut self) -> Result<(), IError> {
+        let dev = self.dev;
+        let mut lock = dev.lock()?;
+        lock.set_gain(0xF000, ChannelAddress::AllCh)?;
+        lock.set_offset(0x8000, ChannelAddress::AllCh)?;
         lock._ldac.reset().unwrap_or_default();
         loop {
-            self.inner_run(&mut lock);
+            self.inner_run(&mut lock)?;
 
             match self.done_ch.try_recv() {
                 Ok(Action::Stop) | Err(TryRecvError::Disconnected) => {
@@ -93,5 +98,6 @@ impl SinExeciter {
                 }) => self.set_code_freq(channel, code, freq),
             }
         }
+        Ok(())
     }
 }
@@ -7,9 +7,13 @@ use actix_web::{
 };
 use serde::{Deserialize, Serialize};
 
-use std::sync::Mutex;
+use std::{convert::TryFrom, sync::Mutex};
 
-use crate::dac::ad537x::Instance;
+use crate::{
+    dac::ad537x::{device, reg::ChannelAddress, verify::VerifyStats, Instance},
+    error::IError,
+    registry::{self, BoardChannel},
+};
 
 #[post("/ping")]
 pub async fn ping() -> Result<HttpResponse> {
@@ -27,13 +31,77 @@ pub struct SetVoltageReq {
     gain: Option<u16>,
 }
 
+impl SetVoltageReq {
+    /// Channel addressed by the request, rejecting negative or out-of-range fields.
+    fn target(&self) -> Result<ChannelAddress, IError> {
+        let group = u8::try_from(self.group).map_err(|_| IError::InvalidChannel {
+            what: "group",
+            value: self.group.into(),
+        })?;
+        let ch = u8::try_from(self.channel).map_err(|_| IError::InvalidChannel {
+            what: "channel",
+            value: self.channel.into(),
+        })?;
+        ChannelAddress::single(group, ch)
+    }
+}
+
 #[post("/voltage")]
 pub async fn voltage(
     ins: web::Data<Mutex<Instance<'_>>>,
     req: web::Json<SetVoltageReq>,
 ) -> Result<String, crate::error::IError> {
+    req.target()?;
     // let mut m = ins.reset();
-    ins.lock().unwrap().reset()?;
+    ins.lock()?.reset()?;
 
     return Ok(format!("{:?}", req));
 }
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SetCodeReq {
+    /// "board:channel", e.g. "rack2:17".
+    target: String,
+    code: u16,
+}
+
+/// Writes the code and pulses LDAC, so the output changes with the request.
+#[post("/code")]
+pub async fn code(req: web::Json<SetCodeReq>) -> Result<String, IError> {
+    let target: BoardChannel = req.target.parse()?;
+    let mut dev = target.device()?.lock()?;
+    dev.set_code(req.code, target.channel.into())?;
+    dev.pulse_ldac()?;
+    Ok(format!("{:?}", req))
+}
+
+#[derive(Debug, Serialize)]
+pub struct BoardsResp {
+    /// Registered DAC boards.
+    names: Vec<String>,
+    /// Serial numbers of the attached FT4232H adapters.
+    adapters: Vec<String>,
+}
+
+#[post("/boards")]
+pub async fn boards() -> Result<HttpResponse, IError> {
+    let adapters = device::list_boards()?
+        .into_iter()
+        .map(|b| b.serial)
+        .collect();
+    Ok(HttpResponse::Ok().json(BoardsResp {
+        names: registry::names()?,
+        adapters,
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StatsReq {
+    board: String,
+}
+
+#[post("/stats")]
+pub async fn stats(req: web::Json<StatsReq>) -> Result<web::Json<VerifyStats>, IError> {
+    let stats = registry::get(&req.board)?.lock()?.verify_stats();
+    Ok(web::Json(stats))
+}